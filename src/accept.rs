@@ -0,0 +1,81 @@
+// What to do when `accept` fails.
+// Most accept errors are about a single connection or a temporary shortage of resources,
+// and shouldn't take the whole server down.
+use std::io;
+use std::time::Duration;
+
+// How long to stop accepting for when we've run out of file descriptors or memory.
+pub const BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    // nothing went wrong, just try again
+    Retry,
+    // that one connection is gone, carry on with the next
+    Skip,
+    // we're out of resources: pause accepting for `BACKOFF` so connections can finish
+    Backoff,
+    // the listener itself is broken
+    Fatal,
+}
+
+pub fn classify(e: &io::Error) -> Recovery {
+    match e.raw_os_error() {
+        Some(libc::EINTR | libc::EAGAIN) => Recovery::Retry,
+        // the client went away before we got to it, or accept(2) is passing on a network
+        // error for the pending connection, which the man page says to treat like EAGAIN
+        Some(
+            libc::ECONNABORTED
+            | libc::EPROTO
+            | libc::EPERM
+            | libc::ENETDOWN
+            | libc::ENOPROTOOPT
+            | libc::EHOSTDOWN
+            | libc::ENONET
+            | libc::EHOSTUNREACH
+            | libc::ENETUNREACH,
+        ) => Recovery::Skip,
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => Recovery::Backoff,
+        _ => Recovery::Fatal,
+    }
+}
+
+// Log an accept error and work out how to recover from it.
+//
+// Panics if the error is fatal.
+pub fn recover(e: io::Error) -> Recovery {
+    let recovery = classify(&e);
+    match recovery {
+        Recovery::Retry => {}
+        Recovery::Skip => println!("failed to accept connection: {e}"),
+        Recovery::Backoff => println!(
+            "failed to accept connection: {e}, pausing for {}ms",
+            BACKOFF.as_millis()
+        ),
+        Recovery::Fatal => panic!("encountered IO error: {e}"),
+    }
+    recovery
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_accept_errors() {
+        let cases = [
+            (libc::EINTR, Recovery::Retry),
+            (libc::ECONNABORTED, Recovery::Skip),
+            (libc::EPROTO, Recovery::Skip),
+            (libc::EMFILE, Recovery::Backoff),
+            (libc::ENFILE, Recovery::Backoff),
+            (libc::ENOBUFS, Recovery::Backoff),
+            (libc::EBADF, Recovery::Fatal),
+            (libc::EINVAL, Recovery::Fatal),
+        ];
+        for (errno, recovery) in cases {
+            let e = io::Error::from_raw_os_error(errno);
+            assert_eq!(classify(&e), recovery, "{e}");
+        }
+    }
+}
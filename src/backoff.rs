@@ -0,0 +1,78 @@
+// Waiting for something we can't block on, without burning a whole core while nothing happens.
+// We check often while things are busy, and back off exponentially the longer it stays quiet.
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+
+const MIN_DELAY: Duration = Duration::from_micros(10);
+// caps how late we can be to notice something new once we've backed off all the way
+const MAX_DELAY: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpinMode {
+    // check again straight away, as fast as the CPU allows
+    Busy,
+    // back off when there's nothing to do
+    Adaptive,
+}
+
+impl FromStr for SpinMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "busy" => Ok(SpinMode::Busy),
+            "adaptive" => Ok(SpinMode::Adaptive),
+            _ => Err(format!(
+                "unknown spin mode {s:?}, expected busy or adaptive"
+            )),
+        }
+    }
+}
+
+pub struct Backoff {
+    mode: SpinMode,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(mode: SpinMode) -> Self {
+        Backoff {
+            mode,
+            delay: MIN_DELAY,
+        }
+    }
+
+    // Nothing to do this time round: wait a little longer than last time before checking again.
+    pub fn idle(&mut self) {
+        match self.mode {
+            SpinMode::Busy => std::hint::spin_loop(),
+            SpinMode::Adaptive => {
+                sleep(self.delay);
+                self.delay = (self.delay * 2).min(MAX_DELAY);
+            }
+        }
+    }
+
+    // Something happened, so more probably will: go back to checking eagerly.
+    pub fn reset(&mut self) {
+        self.delay = MIN_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_up_to_a_limit() {
+        let mut backoff = Backoff::new(SpinMode::Adaptive);
+        for _ in 0..20 {
+            backoff.idle();
+        }
+        assert_eq!(backoff.delay, MAX_DELAY);
+
+        backoff.reset();
+        assert_eq!(backoff.delay, MIN_DELAY);
+    }
+}
@@ -0,0 +1,643 @@
+// A load generator, so the servers can be compared without reaching for an external tool.
+// Every client runs on one thread, driven by mio: each connection sends its requests one after
+// the other, and we time each from when it's sent (or its connection opened) to the end of the
+// response.
+use crate::config;
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    // clients sending requests at the same time
+    pub connections: usize,
+    // requests each client sends, one after the other
+    pub requests: usize,
+    // ask to reuse connections, opening a new one whenever the server closes it
+    pub keep_alive: bool,
+    // how long a request gets before we count it as failed
+    pub timeout: Duration,
+}
+
+impl Default for Load {
+    fn default() -> Self {
+        Load {
+            connections: 50,
+            requests: 20,
+            keep_alive: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: learning-async-rust-web-servers bench [OPTIONS]
+
+Sends requests to a running server and reports throughput and latency.
+
+Options:
+    --target <ADDRESS>           server to load [default: 127.0.0.1:3000]
+    --connections <N>            clients sending requests at once [default: 50]
+    --requests <N>               requests each client sends in turn [default: 20]
+    --keep-alive                 reuse connections the server leaves open
+    --timeout-ms <MS>            time a request gets before it counts as failed
+                                 [default: 10000]
+    -h, --help                   print this help
+";
+
+pub fn main(options: &[String]) -> ExitCode {
+    let (target, load) = match parse_options(options) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}. See bench --help for the options.");
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "{} connections sending {} requests each to {target}, keep-alive {}",
+        load.connections,
+        load.requests,
+        if load.keep_alive { "on" } else { "off" },
+    );
+    match run(target, &load) {
+        Ok(report) => {
+            print!("{report}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Couldn't run the benchmark: {e}.");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_options(options: &[String]) -> Result<(SocketAddr, Load), String> {
+    let mut target = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let mut load = Load::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if option == "--target" {
+            target = options
+                .next()
+                .and_then(|target| target.to_socket_addrs().ok()?.next())
+                .ok_or("--target needs an address like 127.0.0.1:3000")?;
+        } else if !load.parse_option(option, &mut options)? {
+            return Err(format!("Unknown option: {option}"));
+        }
+    }
+    Ok((target, load))
+}
+
+impl Load {
+    // Take `option`, and its value from `values` if it has one, returning whether it's one of
+    // ours.
+    pub fn parse_option<'a>(
+        &mut self,
+        option: &str,
+        values: &mut impl Iterator<Item = &'a String>,
+    ) -> Result<bool, String> {
+        let name = option.trim_start_matches("--");
+        match option {
+            "--keep-alive" => self.keep_alive = true,
+            "--connections" => self.connections = positive(name, values.next())?,
+            "--requests" => self.requests = positive(name, values.next())?,
+            "--timeout-ms" => self.timeout = Duration::from_millis(positive(name, values.next())?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn positive<T: std::str::FromStr + Default + PartialEq>(
+    name: &str,
+    value: Option<&String>,
+) -> Result<T, String> {
+    config::positive(name, value.map(String::as_str)).map_err(|e| format!("--{e}"))
+}
+
+// Requests that didn't get a 200, by what went wrong.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Errors {
+    // couldn't open the connection
+    pub connect: usize,
+    // reading or writing failed, or the server hung up partway through a response
+    pub io: usize,
+    // no response in time
+    pub timeout: usize,
+    // a response other than 200
+    pub status: usize,
+}
+
+impl Errors {
+    pub fn total(&self) -> usize {
+        self.connect + self.io + self.timeout + self.status
+    }
+}
+
+pub struct Report {
+    // from the first connection opening to the last response
+    pub elapsed: Duration,
+    // how long each successful request took, shortest first
+    pub latencies: Vec<Duration>,
+    pub errors: Errors,
+}
+
+impl Report {
+    // Successful requests per second.
+    pub fn throughput(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // The latency `p` percent of successful requests came in under, or zero without any.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2?}: {:.1} req/s",
+            self.latencies.len() + self.errors.total(),
+            self.elapsed,
+            self.throughput()
+        )?;
+        let Errors {
+            connect,
+            io,
+            timeout,
+            status,
+        } = self.errors;
+        writeln!(
+            f,
+            "errors: {} (connect {connect}, io {io}, timeout {timeout}, status {status})",
+            self.errors.total()
+        )?;
+        writeln!(
+            f,
+            "latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )
+    }
+}
+
+// One simulated user, working through its requests.
+struct Client {
+    // closed between requests unless we're keeping connections alive
+    stream: Option<TcpStream>,
+    state: ClientState,
+    // when the current request started
+    started: Instant,
+    // requests still to send after the current one
+    left: usize,
+    response: Vec<u8>,
+}
+
+enum ClientState {
+    Connecting,
+    Writing { written: usize },
+    Reading,
+    Done,
+}
+
+// How far through a response we are.
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Incomplete,
+    // `close` if the server won't take another request on the connection
+    Complete { ok: bool, close: bool },
+}
+
+pub fn run(target: SocketAddr, load: &Load) -> io::Result<Report> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {target}\r\nConnection: {}\r\n\r\n",
+        if load.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        }
+    );
+    let mut report = Report {
+        elapsed: Duration::ZERO,
+        latencies: Vec::with_capacity(load.connections * load.requests),
+        errors: Errors::default(),
+    };
+
+    let start = Instant::now();
+    let mut clients: Vec<Client> = (0..load.connections)
+        .map(|_| Client {
+            stream: None,
+            state: ClientState::Connecting,
+            started: start,
+            left: load.requests - 1,
+            response: Vec::new(),
+        })
+        .collect();
+    let mut running = clients.len();
+    for (i, client) in clients.iter_mut().enumerate() {
+        if !client.begin(&poll, Token(i), target, &mut report.errors) {
+            running -= 1;
+        }
+    }
+
+    while running > 0 {
+        // wake up in time for the first request to run out of time
+        let next_timeout = clients
+            .iter()
+            .filter(|client| !matches!(client.state, ClientState::Done))
+            .map(|client| client.started + load.timeout)
+            .min();
+        let timeout = next_timeout.map(|at| at.saturating_duration_since(Instant::now()));
+        match poll.poll(&mut events, timeout) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        for event in events.iter() {
+            let Token(i) = event.token();
+            let client = &mut clients[i];
+            let Some(outcome) = client.progress(request.as_bytes()) else {
+                continue;
+            };
+            client.finish(&poll, outcome, &mut report);
+            if !client.begin(&poll, Token(i), target, &mut report.errors) {
+                running -= 1;
+            }
+        }
+
+        // give up on requests that have had long enough
+        for (i, client) in clients.iter_mut().enumerate() {
+            let expired = Instant::now() >= client.started + load.timeout;
+            if matches!(client.state, ClientState::Done) || !expired {
+                continue;
+            }
+            client.finish(&poll, Err(Failure::Timeout), &mut report);
+            if !client.begin(&poll, Token(i), target, &mut report.errors) {
+                running -= 1;
+            }
+        }
+    }
+
+    report.elapsed = start.elapsed();
+    report.latencies.sort();
+    Ok(report)
+}
+
+// Why a request failed.
+enum Failure {
+    Io,
+    Timeout,
+    Status,
+}
+
+impl Client {
+    // Start the next request, if there is one, returning whether the client is still going.
+    // Connection failures are counted and moved past, so this only stops once it's out of
+    // requests.
+    fn begin(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        target: SocketAddr,
+        errors: &mut Errors,
+    ) -> bool {
+        loop {
+            if matches!(self.state, ClientState::Done) {
+                return false;
+            }
+            self.started = Instant::now();
+            self.response.clear();
+            if let Some(stream) = &mut self.stream {
+                self.state = ClientState::Writing { written: 0 };
+                // registering it again gets us a fresh writable event to send the request on
+                // (if that fails, the request times out)
+                let _ = poll.registry().reregister(
+                    stream,
+                    token,
+                    Interest::READABLE | Interest::WRITABLE,
+                );
+                return true;
+            }
+
+            let connected = TcpStream::connect(target).and_then(|mut stream| {
+                poll.registry().register(
+                    &mut stream,
+                    token,
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                Ok(stream)
+            });
+            match connected {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.state = ClientState::Connecting;
+                    return true;
+                }
+                Err(_) => {
+                    errors.connect += 1;
+                    self.next();
+                }
+            }
+        }
+    }
+
+    // Move on from the current request.
+    fn next(&mut self) {
+        if self.left == 0 {
+            self.state = ClientState::Done;
+        } else {
+            self.left -= 1;
+            // anything other than `Done` will do until `begin` picks a state
+            self.state = ClientState::Connecting;
+        }
+    }
+
+    // Do as much of the current request as we can, returning how it went once it's over.
+    fn progress(&mut self, request: &[u8]) -> Option<Result<bool, Failure>> {
+        let stream = self.stream.as_mut()?;
+        loop {
+            match &mut self.state {
+                ClientState::Connecting => {
+                    // a connection that failed to open still says it's writable
+                    match stream.take_error() {
+                        Ok(None) => {}
+                        Ok(Some(_)) | Err(_) => return Some(Err(Failure::Io)),
+                    }
+                    match stream.peer_addr() {
+                        Ok(_) => self.state = ClientState::Writing { written: 0 },
+                        Err(e) if e.kind() == io::ErrorKind::NotConnected => return None,
+                        Err(_) => return Some(Err(Failure::Io)),
+                    }
+                }
+                ClientState::Writing { written } => match stream.write(&request[*written..]) {
+                    Ok(n) => {
+                        *written += n;
+                        if *written == request.len() {
+                            self.state = ClientState::Reading;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => return Some(Err(Failure::Io)),
+                },
+                ClientState::Reading => {
+                    let mut buf = [0u8; 4096];
+                    let eof = match stream.read(&mut buf) {
+                        Ok(0) => true,
+                        Ok(n) => {
+                            self.response.extend_from_slice(&buf[..n]);
+                            false
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => return Some(Err(Failure::Io)),
+                    };
+                    match parse(&self.response, eof) {
+                        Response::Incomplete => {}
+                        Response::Complete { ok: true, close } => return Some(Ok(close)),
+                        Response::Complete { ok: false, .. } if eof => {
+                            // the server hung up on us, or sent something other than HTTP
+                            if self.response.is_empty() || !self.response.starts_with(b"HTTP/") {
+                                return Some(Err(Failure::Io));
+                            }
+                            return Some(Err(Failure::Status));
+                        }
+                        Response::Complete { ok: false, .. } => return Some(Err(Failure::Status)),
+                    }
+                }
+                ClientState::Done => return None,
+            }
+        }
+    }
+
+    // Record how the current request went, closing the connection unless it can be reused.
+    fn finish(&mut self, poll: &Poll, outcome: Result<bool, Failure>, report: &mut Report) {
+        let close = match outcome {
+            Ok(close) => {
+                report.latencies.push(self.started.elapsed());
+                close
+            }
+            Err(failure) => {
+                match failure {
+                    Failure::Io => report.errors.io += 1,
+                    Failure::Timeout => report.errors.timeout += 1,
+                    Failure::Status => report.errors.status += 1,
+                }
+                true
+            }
+        };
+        if close {
+            if let Some(mut stream) = self.stream.take() {
+                let _ = poll.registry().deregister(&mut stream);
+            }
+        }
+        self.next();
+    }
+}
+
+// Work out whether `response` is all there, and whether it was a success.
+fn parse(response: &[u8], eof: bool) -> Response {
+    let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return if eof {
+            Response::Complete {
+                ok: false,
+                close: true,
+            }
+        } else {
+            Response::Incomplete
+        };
+    };
+
+    let head = String::from_utf8_lossy(&response[..end]);
+    let ok = head.starts_with("HTTP/1.1 200 ") || head.starts_with("HTTP/1.0 200 ");
+    let mut length = None;
+    let mut close = eof;
+    // `lines` copes with headers ending in a bare newline, as ours do
+    for line in head.lines().skip(1) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            length = value.parse::<usize>().ok();
+        } else if name.trim().eq_ignore_ascii_case("connection") {
+            close |= value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    let body = response.len() - (end + 4);
+    match length {
+        Some(length) if body >= length => Response::Complete { ok, close },
+        // without a length, the body runs until the server hangs up
+        None if eof => Response::Complete { ok, close },
+        // cut short
+        _ if eof => Response::Complete {
+            ok: false,
+            close: true,
+        },
+        _ => Response::Incomplete,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::{mio, testing};
+    use std::thread::spawn;
+
+    fn load(connections: usize, requests: usize) -> Load {
+        Load {
+            connections,
+            requests,
+            ..Load::default()
+        }
+    }
+
+    #[test]
+    fn times_every_request() {
+        let addr = testing::spawn_server(|listener| {
+            mio::serve(listener, Config::default(), testing::hello_world())
+        });
+
+        for keep_alive in [false, true] {
+            let report = run(
+                addr,
+                &Load {
+                    keep_alive,
+                    ..load(10, 5)
+                },
+            )
+            .unwrap();
+            assert_eq!(report.errors, Errors::default());
+            assert_eq!(report.latencies.len(), 50);
+            // every request does the default 10ms of work
+            assert!(report.percentile(0.0) >= Duration::from_millis(10));
+            assert!(report.percentile(50.0) <= report.percentile(100.0));
+        }
+    }
+
+    #[test]
+    fn counts_what_went_wrong() {
+        // nothing listening
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let report = run(addr, &load(2, 3)).unwrap();
+        assert_eq!(report.errors.connect + report.errors.io, 6, "{report}");
+
+        // a server that hangs up without answering, one that answers with an error and one
+        // that never answers at all
+        for (response, expected) in [
+            (
+                None,
+                Errors {
+                    io: 2,
+                    ..Errors::default()
+                },
+            ),
+            (
+                Some(&b"HTTP/1.1 503 Busy\r\nContent-Length: 0\r\n\r\n"[..]),
+                Errors {
+                    status: 2,
+                    ..Errors::default()
+                },
+            ),
+            (
+                Some(&b""[..]),
+                Errors {
+                    timeout: 2,
+                    ..Errors::default()
+                },
+            ),
+        ] {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(move || {
+                let mut open = Vec::new();
+                for connection in listener.incoming() {
+                    let mut connection = connection.unwrap();
+                    let _ = connection.read(&mut [0; 1024]);
+                    match response {
+                        Some(response) => {
+                            connection.write_all(response).unwrap();
+                            open.push(connection);
+                        }
+                        None => drop(connection),
+                    }
+                }
+            });
+
+            let report = run(
+                addr,
+                &Load {
+                    timeout: Duration::from_millis(200),
+                    ..load(2, 1)
+                },
+            )
+            .unwrap();
+            assert_eq!(report.errors, expected, "{report}");
+            assert!(report.latencies.is_empty());
+        }
+    }
+
+    #[test]
+    fn parses_responses() {
+        let ours =
+            b"HTTP/1.1 200 OK\r\nContent-Length: 13\nConnection: close\r\n\r\nHello world!\n";
+        assert_eq!(
+            parse(ours, false),
+            Response::Complete {
+                ok: true,
+                close: true
+            }
+        );
+        assert_eq!(parse(&ours[..ours.len() - 1], false), Response::Incomplete);
+        assert_eq!(
+            parse(&ours[..ours.len() - 1], true),
+            Response::Complete {
+                ok: false,
+                close: true
+            }
+        );
+
+        let kept = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+        assert_eq!(
+            parse(kept, false),
+            Response::Complete {
+                ok: true,
+                close: false
+            }
+        );
+    }
+
+    #[test]
+    fn picks_percentiles() {
+        let report = Report {
+            elapsed: Duration::from_secs(1),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            errors: Errors::default(),
+        };
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(report.throughput(), 100.0);
+    }
+}
@@ -1,26 +1,26 @@
-// polling-based multiplexed I/O
-// currently hangs if there are >2 clients :<
-use std::collections::HashMap;
+// polling-based multiplexed I/O: a single-threaded epoll event loop
+// Build with `--features trace` to see what the event loop is up to.
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::error::ConnectionError;
+use crate::handler::{self, SharedHandler};
+use crate::limit::{InFlight, InFlightGuard, Limit};
+use crate::server;
+use crate::shutdown;
+use crate::timeout::{self, Timeouts};
+use std::collections::{BTreeSet, HashMap};
 use std::io;
 use std::io::{Read, Write};
-// use std::os::fd::AsRawFd;
 
-use mio::net::TcpListener;
+use mio::net::{TcpListener, TcpStream};
 use mio::{Events, Interest, Poll, Token};
 
-use std::thread::sleep;
-use std::time::Duration;
+use std::time::Instant;
 
 #[allow(clippy::large_enum_variant)]
 enum ConnectionState {
-    ReadingRequest {
-        request: [u8; 1024],
-        read: usize,
-    },
-    WritingResponse {
-        response: &'static [u8],
-        written: usize,
-    },
+    ReadingRequest { request: [u8; 1024], read: usize },
+    WritingResponse { response: Vec<u8>, written: usize },
     Flushing,
 }
 
@@ -37,71 +37,127 @@ impl TaskId {
     }
 }
 
+// only prints when the `trace` feature is enabled
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "trace") {
+            println!($($arg)*);
+        }
+    };
+}
+
 // Some token to allow us to identify which event is for the listener
 const LISTENER: Token = Token(0);
+// and one for the pipe that tells us we've been asked to shut down
+const SIGNALS: Token = Token(usize::MAX);
+
+pub fn serve(listener: std::net::TcpListener, config: Config, handler: SharedHandler) {
+    listener.set_nonblocking(true).unwrap();
+    let mut listener = TcpListener::from_std(listener);
+    let mut signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    let mut limit = Limit::new(config.max_connections);
 
-pub fn main() {
     // create poll
     let mut poll = Poll::new().unwrap();
 
-    // bind the listener
-    let mut listener = TcpListener::bind("127.0.0.1:3000".parse().unwrap()).unwrap();
-
     // register the listener
     poll.registry()
         .register(&mut listener, LISTENER, Interest::READABLE)
         .unwrap();
+    poll.registry()
+        .register(&mut signals, SIGNALS, Interest::READABLE)
+        .unwrap();
+    server::ready(&in_flight);
 
+    let mut listener = Some(listener);
     let mut connections = HashMap::new();
+    // when each connection has to be done with what it's up to, soonest first
+    // (entries stay behind when a connection moves on, so check they're still current)
+    let mut deadlines = BTreeSet::new();
+    // set when we've run out of resources, so existing connections get a chance to finish
+    let mut paused_until: Option<Instant> = None;
+    // set once we've been asked to shut down
+    let mut drain_deadline: Option<Instant> = None;
 
     let mut events = Events::with_capacity(1024);
     loop {
-        // block until poll wakes us up
-        poll.poll(&mut events, Some(Duration::new(5, 0))).unwrap();
+        // block until poll wakes us up, or it's time to start accepting again, give up on a slow
+        // client or give up draining
+        let next_deadline = deadlines.first().map(|&(deadline, _)| deadline);
+        let wake_at = [paused_until, drain_deadline, next_deadline]
+            .into_iter()
+            .flatten()
+            .min();
+        let timeout = wake_at.map(|at| at.saturating_duration_since(Instant::now()));
+        match poll.poll(&mut events, timeout) {
+            Ok(()) => {}
+            // interrupted, a shutdown request comes through the pipe
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => panic!("encountered IO error: {e}"),
+        }
         let mut completed = Vec::new();
 
-        println!(
-            "{:#?}",
-            events // .iter()
-                   // .map(|event| { event.token() })
-                   // .collect::<Vec<Token>>()
-        );
+        trace!("{:#?}", events);
+
+        if paused_until.is_some_and(|until| Instant::now() >= until) {
+            // connections that arrived while we were paused won't get their own event
+            paused_until = listener.as_mut().and_then(|listener| {
+                accept_connections(
+                    listener,
+                    &poll,
+                    &mut connections,
+                    &mut deadlines,
+                    &mut limit,
+                    &config.timeouts,
+                    &in_flight,
+                )
+            });
+        }
 
         'next: for event in events.iter() {
             let token = event.token();
             // is the listener ready with a new connection?
-            println!("processing event for token {:}", token.0);
+            trace!("processing event for token {:}", token.0);
+            if token == SIGNALS {
+                if let Some(mut listener) = listener.take() {
+                    // stop accepting new connections, and give the ones we have time to finish
+                    poll.registry().deregister(&mut listener).unwrap();
+                    drain_deadline = Some(Instant::now() + shutdown::DRAIN_TIMEOUT);
+                }
+                continue 'next;
+            }
             if token == LISTENER {
-                match listener.accept() {
-                    Ok((mut connection, _)) => {
-                        let id = TaskId::new();
-                        println!("accepted connection: {:?}", id);
-
-                        // add the connection to the poller
-                        poll.registry()
-                            .register(&mut connection, Token(id.0), Interest::READABLE)
-                            .unwrap();
-
-                        // keep track of connection state
-                        let state = ConnectionState::ReadingRequest {
-                            request: [0u8; 1024],
-                            read: 0,
-                        };
-
-                        connections.insert(id.0, (connection, state));
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        println!("blocked in listener");
-                    }
-                    Err(e) => panic!("encountered IO error: {}", e),
+                if let (Some(listener), None) = (&mut listener, paused_until) {
+                    paused_until = accept_connections(
+                        listener,
+                        &poll,
+                        &mut connections,
+                        &mut deadlines,
+                        &mut limit,
+                        &config.timeouts,
+                        &in_flight,
+                    );
                 }
                 continue 'next;
             }
             // otherwise, it must be a connection
-            let (connection, state) = connections.get_mut(&token.0).unwrap();
+            let Some((connection, state, deadline, _)) = connections.get_mut(&token.0) else {
+                trace!("event for unknown connection {:}", token.0);
+                continue 'next;
+            };
+
+            // the socket is in an error state, or both directions are shut: nothing more to do
+            // (if only the client's half is closed, reading will tell us with `Ok(0)`)
+            if event.is_error() || (event.is_read_closed() && event.is_write_closed()) {
+                println!("client disconnected unexpectedly");
+                completed.push(token.0);
+                continue 'next;
+            }
+
             // is the connection readable?
             if let ConnectionState::ReadingRequest { request, read } = state {
-                println!("reading from {:}", token.0);
+                trace!("reading from {:}", token.0);
                 loop {
                     match connection.read(&mut request[*read..]) {
                         Ok(0) => {
@@ -110,48 +166,68 @@ pub fn main() {
                             continue 'next;
                         }
                         Ok(num_bytes) => {
+                            // the request has started, it gets the header timeout to finish
+                            if *read == 0 {
+                                *deadline = Instant::now() + config.timeouts.header;
+                                deadlines.insert((*deadline, token.0));
+                            }
                             // keep track of how many bytes we've read
                             *read += num_bytes;
                         }
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            println!("blocked on read");
+                            // nothing more to read for now, wait until poll says there is
+                            trace!("blocked on read");
+                            continue 'next;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        // something went wrong with this connection, give up on it
+                        Err(e) => {
+                            println!("failed to handle connection: {}", ConnectionError::from(e));
+                            completed.push(token.0);
+                            continue 'next;
                         }
-                        Err(e) => panic!("encountered IO error: {e}"),
                     }
 
                     // have we reached the end of the request?
-                    if *read >= 4 {
-                        if request.get(*read - 4..*read) == Some(b"\r\n\r\n") {
-                            break;
-                        }
+                    if *read >= 4 && request.get(*read - 4..*read) == Some(b"\r\n\r\n") {
+                        break;
+                    }
+                    // or run out of room for it?
+                    if *read == request.len() {
+                        let e = handler::request_too_large(connection);
+                        println!("failed to handle connection: {e}");
+                        completed.push(token.0);
+                        continue 'next;
                     }
                 }
-                let _request = String::from_utf8_lossy(&request[..*read]);
-                // println!("{request}");
-                // sleep for 10 ms to simulate doing some work
-                sleep(Duration::from_millis(10));
-                let response = concat!(
-                    "HTTP/1.1 200 OK\r\n",
-                    "Content-Length: 13\n",
-                    "Connection: close\r\n\r\n",
-                    "Hello world!\n"
-                );
+                // println!("{}", String::from_utf8_lossy(&request[..*read]));
+                // simulate doing some work, holding up every other connection meanwhile
+                config.work.run();
+                let response = handler.respond(&request[..*read]);
 
-                // add the connection to the poller
-                poll.registry()
+                // from now on we only care about being able to write
+                if let Err(e) = poll
+                    .registry()
                     .reregister(connection, token, Interest::WRITABLE)
-                    .unwrap();
+                {
+                    println!("failed to handle connection: {}", ConnectionError::from(e));
+                    completed.push(token.0);
+                    continue 'next;
+                }
 
                 *state = ConnectionState::WritingResponse {
-                    response: response.as_bytes(),
+                    response,
                     written: 0,
-                }
+                };
+                *deadline = Instant::now() + config.timeouts.write;
+                deadlines.insert((*deadline, token.0));
             };
 
             // is the connection writable?
             if let ConnectionState::WritingResponse { response, written } = state {
-                println!("writing to {:?}", token.0);
-                loop {
+                trace!("writing to {:?}", token.0);
+                // until we've written the entire response
+                while *written < response.len() {
                     match connection.write(&response[*written..]) {
                         Ok(0) => {
                             println!("client disconnected unexpectedly");
@@ -163,14 +239,17 @@ pub fn main() {
                             *written += num_bytes;
                         }
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            println!("blocked on write");
+                            // the send buffer is full, wait until poll says there's room
+                            trace!("blocked on write");
+                            continue 'next;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        // something went wrong with this connection, give up on it
+                        Err(e) => {
+                            println!("failed to handle connection: {}", ConnectionError::from(e));
+                            completed.push(token.0);
+                            continue 'next;
                         }
-                        Err(e) => panic!("encountered IO error: {e}"),
-                    }
-
-                    // have we written the entire response?
-                    if *written == response.len() {
-                        break;
                     }
                 }
 
@@ -179,36 +258,166 @@ pub fn main() {
 
             if let ConnectionState::Flushing = state {
                 //try to flush the connection
-                println!("flushing {:?}", token.0);
-                loop {
-                    match connection.flush() {
-                        Ok(()) => {
-                            completed.push(token.0);
-                            break;
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            println!("blocked on flush");
-                        }
-                        Err(e) => {
-                            panic!("encountered IO error: {e}");
-                        }
+                trace!("flushing {:?}", token.0);
+                match connection.flush() {
+                    Ok(()) => completed.push(token.0),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        trace!("blocked on flush");
+                        continue 'next;
+                    }
+                    Err(e) => {
+                        println!("failed to handle connection: {}", ConnectionError::from(e));
+                        completed.push(token.0);
+                        continue 'next;
                     }
                 }
             }
         }
 
+        // give up on connections that have run out of time
+        let now = Instant::now();
+        while let Some(&(deadline, id)) = deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            deadlines.pop_first();
+            let Some((connection, state, current, _)) = connections.get_mut(&id) else {
+                continue;
+            };
+            if *current != deadline {
+                continue;
+            }
+            let e = match state {
+                ConnectionState::ReadingRequest { read, .. } => {
+                    timeout::request_timed_out(connection, *read)
+                }
+                _ => ConnectionError::from(io::Error::from(io::ErrorKind::TimedOut)),
+            };
+            println!("failed to handle connection: {e}");
+            completed.push(id);
+        }
+
         // remove completed connections
         for id in completed.iter() {
-            match connections.remove(&id) {
-                Some((mut connection, _)) => {
-                    poll.registry().deregister(&mut connection).unwrap();
+            match connections.remove(id) {
+                Some((mut connection, ..)) => {
+                    if let Err(e) = poll.registry().deregister(&mut connection) {
+                        println!("failed to deregister connection {id}: {e}");
+                    }
                     drop(connection);
-                    println!("connection closed: {}", id);
+                    trace!("connection closed: {}", id);
                 }
                 None => {
-                    println!("connection not found: {}", id)
+                    trace!("connection not found: {}", id)
+                }
+            }
+        }
+
+        // we stopped accepting at the limit, and won't hear about those connections again
+        if limit.is_full() && !completed.is_empty() {
+            if let (Some(listener), None) = (&mut listener, paused_until) {
+                paused_until = accept_connections(
+                    listener,
+                    &poll,
+                    &mut connections,
+                    &mut deadlines,
+                    &mut limit,
+                    &config.timeouts,
+                    &in_flight,
+                );
+            }
+        }
+
+        if let Some(deadline) = drain_deadline {
+            if connections.is_empty() || Instant::now() >= deadline {
+                shutdown::report(connections.len());
+                return;
+            }
+        }
+    }
+}
+
+// Accept every pending connection we have room for, returning when to try again if we had to
+// back off.
+fn accept_connections(
+    listener: &mut TcpListener,
+    poll: &Poll,
+    connections: &mut HashMap<usize, (TcpStream, ConnectionState, Instant, InFlightGuard)>,
+    deadlines: &mut BTreeSet<(Instant, usize)>,
+    limit: &mut Limit,
+    timeouts: &Timeouts,
+    in_flight: &InFlight,
+) -> Option<Instant> {
+    // we're only told about new connections once (edge-triggered),
+    // so accept until there are none left
+    while limit.has_room(connections.len()) {
+        match listener.accept() {
+            Ok((mut connection, _)) => {
+                let id = TaskId::new();
+                trace!("accepted connection: {:?}", id);
+
+                // add the connection to the poller, we want to read the request first
+                if let Err(e) =
+                    poll.registry()
+                        .register(&mut connection, Token(id.0), Interest::READABLE)
+                {
+                    println!("failed to handle connection: {}", ConnectionError::from(e));
+                    continue;
+                }
+
+                // keep track of connection state
+                let state = ConnectionState::ReadingRequest {
+                    request: [0u8; 1024],
+                    read: 0,
+                };
+
+                // the client has the idle timeout to start its request
+                let deadline = Instant::now() + timeouts.idle;
+                deadlines.insert((deadline, id.0));
+                connections.insert(id.0, (connection, state, deadline, in_flight.start()));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!("blocked in listener");
+                return None;
+            }
+            Err(e) => {
+                if accept::recover(e) == Recovery::Backoff {
+                    return Some(Instant::now() + accept::BACKOFF);
                 }
             }
         }
     }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn partial_request_does_not_block_other_clients() {
+        use std::io::{Read, Write};
+
+        let addr = testing::spawn_server(|listener| {
+            serve(listener, Config::default(), testing::hello_world())
+        });
+
+        // send half a request and stall
+        let mut slow = std::net::TcpStream::connect(addr).unwrap();
+        slow.write_all(&testing::REQUEST[..10]).unwrap();
+        sleep(Duration::from_millis(50));
+
+        // meanwhile, another client should be served as usual
+        let responses = testing::simultaneous_requests(addr, 1);
+        assert!(responses[0].ends_with("Hello world!\n"));
+
+        // and the slow client once it's done
+        slow.write_all(&testing::REQUEST[10..]).unwrap();
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("Hello world!\n"));
+    }
 }
@@ -0,0 +1,338 @@
+// Puts each version of the server under the same load in turn and tabulates how they did.
+// Each one runs as a child process on a port of its own, so what it costs can be read from
+// /proc while the load generator runs here.
+use crate::bench::{self, Load, Report};
+use crate::config::Settings;
+use crate::procfs;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, ExitCode, Stdio};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+// The versions compared unless told otherwise.
+const DEFAULT_VARIANTS: &[&str] = &[
+    "simple",
+    "multithread",
+    "nonblocking_spin",
+    "nonblocking",
+    "busted_polling",
+    "mio",
+];
+
+// How often to look at each server's threads and file descriptors while it's under load.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+pub const USAGE: &str = "\
+Usage: learning-async-rust-web-servers compare [OPTIONS]
+
+Runs each version of the server under the same load and prints a table of how they did.
+
+Options:
+    --variants <NAMES>           comma-separated versions to compare, any but futures
+                                 and final [default: simple,multithread,nonblocking_spin,
+                                 nonblocking,busted_polling,mio]
+    --connections <N>            clients sending requests at once [default: 50]
+    --requests <N>               requests each client sends in turn [default: 20]
+    --keep-alive                 reuse connections the servers leave open
+    --timeout-ms <MS>            time a request gets before it counts as failed
+                                 [default: 10000]
+    -h, --help                   print this help
+
+Any of the servers' own options, like --simulated-work-ms or --workers, are passed on to
+every one of them, except --port: each one gets a free port of its own.
+";
+
+// How one version did.
+pub struct Row {
+    pub report: Report,
+    // CPU time the server used while under load
+    pub cpu: Duration,
+    // the most threads and file descriptors it had at once
+    pub threads: usize,
+    pub fds: usize,
+}
+
+pub fn main(options: &[String]) -> ExitCode {
+    let (variants, load, server_options) = match parse_options(options) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}. See compare --help for the options.");
+            return ExitCode::from(2);
+        }
+    };
+
+    println!(
+        "{} connections sending {} requests each, keep-alive {}",
+        load.connections,
+        load.requests,
+        if load.keep_alive { "on" } else { "off" },
+    );
+    println!(
+        "{:<17} {:>9} {:>8} {:>8} {:>8} {:>8} {:>7} {:>8} {:>8} {:>5}",
+        "variant",
+        "req/s",
+        "p50 ms",
+        "p90 ms",
+        "p99 ms",
+        "max ms",
+        "errors",
+        "cpu s",
+        "threads",
+        "fds"
+    );
+    let mut failed = false;
+    for variant in variants {
+        match compare(&variant, &server_options, &load) {
+            Ok(row) => println!("{}", format_row(&variant, &row)),
+            Err(e) => {
+                println!("{variant:<17} failed: {e}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// The versions to run, the load to put them under and the options to start them with.
+fn parse_options(options: &[String]) -> Result<(Vec<String>, Load, Vec<String>), String> {
+    let mut variants: Vec<String> = DEFAULT_VARIANTS.iter().map(|&v| v.into()).collect();
+    let mut load = Load::default();
+    let mut server_options = Vec::new();
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        if option == "--variants" {
+            let names = options
+                .next()
+                .ok_or("--variants needs a list of versions")?;
+            variants = names.split(',').map(String::from).collect();
+            for name in &variants {
+                match crate::VARIANTS.iter().find(|variant| variant.name == name) {
+                    None => return Err(format!("Invalid version specified: {name}")),
+                    // it wouldn't do the same work as the rest, so it can't be compared with them
+                    Some(variant) if !variant.tunable => {
+                        return Err(format!("{name} takes no options, so can't be compared"))
+                    }
+                    Some(_) => {}
+                }
+            }
+        } else if !load.parse_option(option, &mut options)? {
+            // every server option takes a value
+            let value = options.next();
+            // they'd all be after the same one, and be in each other's way
+            if option == "--port" {
+                return Err("compare picks a free port for each server".into());
+            }
+            server_options.push(option.clone());
+            server_options.extend(value.cloned());
+        }
+    }
+
+    // checked here, so a typo doesn't show up as every server failing to start
+    let settings = Settings::from_options(&server_options)?;
+    if !settings.listeners.is_empty() {
+        return Err("compare picks a free port for each server, so can't take listen".into());
+    }
+    Ok((variants, load, server_options))
+}
+
+// Start `variant` on a free port, put it under `load`, then stop it.
+fn compare(variant: &str, server_options: &[String], load: &Load) -> io::Result<Row> {
+    // the port goes last, where nothing in the options can override it
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg(variant)
+        .args(server_options)
+        .args(["--port", "0"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let row =
+        listening_on(&mut child).and_then(|addr| measure(&child.id().to_string(), addr, load));
+    let _ = child.kill();
+    let _ = child.wait();
+    row
+}
+
+// Wait for `child` to say where it's listening, then keep draining what it prints so it never
+// blocks on a full pipe.
+fn listening_on(child: &mut Child) -> io::Result<SocketAddr> {
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let addr = loop {
+        let Some(line) = lines.next().transpose()? else {
+            return Err(io::Error::other(
+                "the server exited before it was listening",
+            ));
+        };
+        if let Some((_, addr)) = line.split_once("listening on ") {
+            break addr.parse().map_err(io::Error::other)?;
+        }
+    };
+    spawn(move || lines.for_each(drop));
+    Ok(addr)
+}
+
+// Put the server at `addr`, which is process `pid`, under `load`, keeping an eye on what it uses
+// meanwhile.
+pub fn measure(pid: &str, addr: SocketAddr, load: &Load) -> io::Result<Row> {
+    let (mut threads, mut fds) = (0, 0);
+    let mut sample = || -> io::Result<()> {
+        threads = threads.max(procfs::threads(pid)?);
+        fds = fds.max(procfs::open_fds(pid)?);
+        Ok(())
+    };
+
+    // once before the load and once after, so even a run shorter than the sampling interval
+    // gets the server's threads and fds
+    sample()?;
+    let cpu_before = procfs::cpu_time(pid)?;
+    let load = *load;
+    let bench = spawn(move || bench::run(addr, &load));
+    while !bench.is_finished() {
+        sleep(SAMPLE_INTERVAL);
+        sample()?;
+    }
+    let report = bench.join().unwrap()?;
+    sample()?;
+
+    Ok(Row {
+        report,
+        cpu: procfs::cpu_time(pid)? - cpu_before,
+        threads,
+        fds,
+    })
+}
+
+fn format_row(variant: &str, row: &Row) -> String {
+    let ms = |p| format!("{:.1}", row.report.percentile(p).as_secs_f64() * 1000.0);
+    format!(
+        "{variant:<17} {:>9.1} {:>8} {:>8} {:>8} {:>8} {:>7} {:>8.2} {:>8} {:>5}",
+        row.report.throughput(),
+        ms(50.0),
+        ms(90.0),
+        ms(99.0),
+        ms(100.0),
+        row.report.errors.total(),
+        row.cpu.as_secs_f64(),
+        row.threads,
+        row.fds
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{self, ChildServer};
+    use crate::{multithread, nonblocking, threadpool};
+
+    fn options(options: &[&str]) -> Vec<String> {
+        options.iter().map(|option| option.to_string()).collect()
+    }
+
+    #[test]
+    fn sorts_out_whose_options_are_whose() {
+        let (variants, load, server_options) = parse_options(&options(&[
+            "--variants",
+            "mio,simple",
+            "--connections",
+            "4",
+            "--simulated-work-ms",
+            "0",
+            "--keep-alive",
+        ]))
+        .unwrap();
+        assert_eq!(variants, ["mio", "simple"]);
+        assert_eq!(load.connections, 4);
+        assert!(load.keep_alive);
+        assert_eq!(server_options, ["--simulated-work-ms", "0"]);
+
+        for bad in [
+            &["--variants", "mio,quick"][..],
+            &["--variants", "mio,futures"],
+            &["--workers", "0"],
+            &["--frobnicate", "1"],
+            &["--port", "8080"],
+            &["--listen", "127.0.0.1:8080"],
+        ] {
+            assert!(parse_options(&options(bad)).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn measures_a_server_under_load() {
+        let test = testing::test_name!(measures_a_server_under_load);
+        let Some(server) = ChildServer::start(
+            test,
+            || {},
+            |listener| multithread::serve(listener, Config::default(), testing::hello_world()),
+        ) else {
+            return;
+        };
+
+        let load = Load {
+            connections: 8,
+            requests: 5,
+            ..Load::default()
+        };
+        let row = measure(&server.id().to_string(), server.addr, &load).unwrap();
+        assert_eq!(row.report.latencies.len(), 40);
+        assert_eq!(row.report.errors.total(), 0);
+        assert!(row.threads >= 1);
+        // stdio, the listener and the signal pipe at least
+        assert!(row.fds >= 5, "{}", row.fds);
+
+        let formatted = format_row("multithread", &row);
+        assert!(formatted.starts_with("multithread "), "{formatted}");
+    }
+
+    #[test]
+    fn samples_a_run_too_short_to_sample_during() {
+        let test = testing::test_name!(samples_a_run_too_short_to_sample_during);
+        let Some(server) = ChildServer::start(
+            test,
+            || {},
+            |listener| threadpool::serve(listener, Config::default(), testing::hello_world()),
+        ) else {
+            return;
+        };
+
+        let load = Load {
+            connections: 1,
+            requests: 1,
+            ..Load::default()
+        };
+        let row = measure(&server.id().to_string(), server.addr, &load).unwrap();
+        // every worker, plus the thread accepting connections
+        let workers = Config::default().pool.workers;
+        assert!(row.threads > workers, "{} threads", row.threads);
+        assert!(row.fds >= 5, "{}", row.fds);
+    }
+
+    #[test]
+    fn fails_if_the_server_goes_away() {
+        let test = testing::test_name!(fails_if_the_server_goes_away);
+        let Some(mut server) = ChildServer::start(
+            test,
+            || {},
+            |listener| nonblocking::serve(listener, Config::default(), testing::hello_world()),
+        ) else {
+            return;
+        };
+        let pid = server.id().to_string();
+        server.signal(libc::SIGKILL);
+        while server.is_running() {
+            sleep(Duration::from_millis(10));
+        }
+
+        let load = Load {
+            connections: 1,
+            requests: 1,
+            ..Load::default()
+        };
+        assert!(measure(&pid, server.addr, &load).is_err());
+    }
+}
@@ -0,0 +1,441 @@
+// Settings shared by the servers. Each variant uses the ones that make sense for it.
+use crate::backoff::SpinMode;
+use crate::limit::DEFAULT_MAX_CONNECTIONS;
+use crate::routes::Routes;
+use crate::shutdown::Signals;
+use crate::threadpool::PoolConfig;
+use crate::timeout::Timeouts;
+use crate::workload::{Workload, PROFILES};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    // where to listen
+    pub addr: SocketAddr,
+    // how many connections to handle at once, for the variants that handle more than one
+    pub max_connections: usize,
+    pub timeouts: Timeouts,
+    // for the threadpool variant
+    pub pool: PoolConfig,
+    // how the non-blocking variants wait when there's nothing to do
+    pub spin: SpinMode,
+    // what each request pretends to do before it gets its response
+    pub work: Workload,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 3000)),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            timeouts: Timeouts::default(),
+            pool: PoolConfig::default(),
+            spin: SpinMode::Adaptive,
+            work: Workload::default(),
+        }
+    }
+}
+
+impl Config {
+    // Change the setting for the command line option `--{name}`. Errors start with the name, so
+    // they can be pointed at the flag or the line in a file.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        match name {
+            "bind" => self.addr.set_ip(
+                value
+                    .and_then(|ip| ip.parse::<IpAddr>().ok())
+                    .ok_or("bind needs an IP address")?,
+            ),
+            "port" => self.addr.set_port(number(name, value)?),
+            "simulated-work-ms" => {
+                self.work = Workload::Sleep(Duration::from_millis(number(name, value)?))
+            }
+            "workload" => {
+                let workload = value.ok_or_else(|| format!("workload needs one of {PROFILES}"))?;
+                self.work = workload.parse().map_err(|e| format!("{name}: {e}"))?;
+            }
+            "max-connections" => self.max_connections = positive(name, value)?,
+            "workers" => self.pool.workers = positive(name, value)?,
+            "queue-size" => self.pool.queue_size = positive(name, value)?,
+            "queue-policy" => {
+                let policy =
+                    value.ok_or("queue-policy needs one of block, reject or drop-oldest")?;
+                self.pool.policy = policy.parse().map_err(|e| format!("{name}: {e}"))?;
+            }
+            "spin" => {
+                let mode = value.ok_or("spin needs one of busy or adaptive")?;
+                self.spin = mode.parse().map_err(|e| format!("{name}: {e}"))?;
+            }
+            "idle-timeout-ms" => self.timeouts.idle = Duration::from_millis(positive(name, value)?),
+            "header-timeout-ms" => {
+                self.timeouts.header = Duration::from_millis(positive(name, value)?)
+            }
+            "write-timeout-ms" => {
+                self.timeouts.write = Duration::from_millis(positive(name, value)?)
+            }
+            _ => return Err(format!("{name} isn't an option")),
+        }
+        Ok(())
+    }
+
+    // Take up the settings the server's been asked to use, if there are any, returning whether
+    // we did. Connections already underway keep the settings they started with. The listener
+    // and the threadpool are only set up once, so changing those needs a restart.
+    pub fn reload(&mut self, signals: &Signals) -> bool {
+        let Some(mut config) = signals.reload_requested() else {
+            return false;
+        };
+
+        if config.addr != self.addr {
+            println!("still listening on {}, moving needs a restart", self.addr);
+            config.addr = self.addr;
+        }
+        let pool = (self.pool.workers, self.pool.queue_size);
+        if (config.pool.workers, config.pool.queue_size) != pool {
+            println!("keeping the threadpool's size, changing it needs a restart");
+            (config.pool.workers, config.pool.queue_size) = pool;
+        }
+        *self = config;
+        println!("reloaded the configuration");
+        true
+    }
+}
+
+// Everything the file and flags can set: the settings each server takes, along with where to
+// listen and what to answer with, which are set up once for all of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub config: Config,
+    // the addresses given with `listen`, in place of the one `bind` and `port` make up
+    pub listeners: Vec<SocketAddr>,
+    pub routes: Routes,
+}
+
+// How `load` and `from_options` apply each setting they find.
+type Setter = dyn Fn(&mut Settings, &str, Option<&str>) -> Result<(), String>;
+
+// The settings that can be given more than once, each adding to a list.
+const LISTS: &[&str] = &["listen", "route", "static"];
+
+impl Settings {
+    // Change the setting for `--{name}`, or add to one of the lists: `listen <ADDRESS>`,
+    // `route <PATH> <TEXT>` or `static <PREFIX> <DIRECTORY>`.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        match name {
+            "listen" => self.listeners.push(
+                value
+                    .and_then(|addr| addr.parse().ok())
+                    .ok_or("listen needs an address like 127.0.0.1:3000")?,
+            ),
+            "route" | "static" => self.routes.add(name, value)?,
+            _ => self.config.set(name, value)?,
+        }
+        Ok(())
+    }
+
+    // Empty the list `name`, for a flag to replace what the file gave.
+    fn clear(&mut self, name: &str) {
+        match name {
+            "listen" => self.listeners.clear(),
+            _ => self.routes.clear(name),
+        }
+    }
+
+    // Every address to listen on.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        if self.listeners.is_empty() {
+            vec![self.config.addr]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    // Apply the settings in the file at `path`: one `name = value` per line, named like the
+    // command line options without their dashes, with `#` starting a comment.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        self.load_with(path, &Settings::set)
+    }
+
+    fn load_with(&mut self, path: &str, set: &Setter) -> Result<(), String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Couldn't read {path}: {e}"))?;
+
+        // where each setting was, so a second one for the same thing can point at the first
+        let mut seen = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let at = format!("{path}:{}", i + 1);
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(format!("{at}: expected `name = value`, found {line:?}"));
+            };
+            let (name, value) = (name.trim(), value.trim());
+            if !LISTS.contains(&name) {
+                if let Some(first) = seen.insert(name.to_string(), i + 1) {
+                    return Err(format!("{at}: {name} is already set on line {first}"));
+                }
+            }
+            let value = Some(value).filter(|value| !value.is_empty());
+            set(self, name, value).map_err(|e| format!("{at}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    // Settings from command line options: `--config <PATH>`, then `--{name} <VALUE>` for
+    // anything `set` takes. The file comes first, so flags override it wherever they appear,
+    // and flags for a list replace the file's.
+    pub fn from_options(options: &[String]) -> Result<Settings, String> {
+        Settings::from_options_with(options, &Settings::set)
+    }
+
+    // Like `from_options`, for the versions that only take where to listen, and only one place
+    // at that: setting anything else, with a flag or in the file, is an error.
+    pub fn from_listen_options(options: &[String]) -> Result<Settings, String> {
+        Settings::from_options_with(options, &|settings, name, value| match name {
+            "bind" | "port" => settings.set(name, value),
+            _ => Err(format!(
+                "{name} can't be set, this version only takes where to listen"
+            )),
+        })
+    }
+
+    fn from_options_with(options: &[String], set: &Setter) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        if let Some(i) = options.iter().position(|option| option == "--config") {
+            let path = options.get(i + 1).ok_or("--config needs a path")?;
+            settings.load_with(path, set)?;
+        }
+
+        // the lists flags have started on
+        let mut replaced = Vec::new();
+        // every option takes a value
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = options.next().map(String::as_str);
+            match option.strip_prefix("--") {
+                Some("config") => {}
+                Some(name) => {
+                    if LISTS.contains(&name) && !replaced.contains(&name) {
+                        settings.clear(name);
+                        replaced.push(name);
+                    }
+                    set(&mut settings, name, value).map_err(|e| format!("--{e}"))?
+                }
+                None => return Err(format!("Unknown option: {option}")),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+// `value`, which has to be a number.
+fn number<T: FromStr>(name: &str, value: Option<&str>) -> Result<T, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("{name} needs a number"))
+}
+
+// `value`, which has to be a positive number.
+pub fn positive<T: FromStr + Default + PartialEq>(
+    name: &str,
+    value: Option<&str>,
+) -> Result<T, String> {
+    match value.and_then(|value| value.parse().ok()) {
+        Some(n) if n != T::default() => Ok(n),
+        _ => Err(format!("{name} needs a positive number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::threadpool::QueuePolicy;
+
+    #[test]
+    fn loads_settings_from_a_file() {
+        let path = testing::temp_file(
+            "# a comment, then a blank line\n\
+             \n\
+             bind = 0.0.0.0\n\
+             port=8080   # trailing comments are fine too\n\
+             queue-policy = reject\n\
+             workload = random:5-50\n\
+             idle-timeout-ms = 500\n",
+        );
+        let mut settings = Settings::default();
+        settings.load(path.to_str().unwrap()).unwrap();
+
+        let config = settings.config;
+        assert_eq!(config.addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.pool.policy, QueuePolicy::Reject);
+        assert_eq!(config.work, "random:5-50".parse().unwrap());
+        assert_eq!(config.timeouts.idle, Duration::from_millis(500));
+        // and the rest are left alone
+        assert_eq!(config.max_connections, Config::default().max_connections);
+    }
+
+    #[test]
+    fn points_at_the_offending_line() {
+        for (contents, error) in [
+            (
+                "port = 3000\nworkers = 0\n",
+                ":2: workers needs a positive number",
+            ),
+            ("\n\nspin = sometimes\n", ":3: spin: unknown spin mode"),
+            ("colour = blue\n", ":1: colour isn't an option"),
+            ("listen = 3000\n", ":1: listen needs an address"),
+            (
+                "route = /health\n",
+                ":1: route needs a path and what to answer",
+            ),
+            ("workload = gpu:1\n", ":1: workload: unknown workload"),
+            ("port 3000\n", ":1: expected `name = value`"),
+            ("bind =\n", ":1: bind needs an IP address"),
+            (
+                "port = 1\n# again\nport = 2\n",
+                ":3: port is already set on line 1",
+            ),
+        ] {
+            let path = testing::temp_file(contents);
+            let path = path.to_str().unwrap();
+            let e = Settings::default().load(path).unwrap_err();
+            assert!(e.starts_with(&format!("{path}{error}")), "{e}");
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_where_to_listen_and_tunables() {
+        let config = Settings::from_options(&args(&[
+            "--bind",
+            "0.0.0.0",
+            "--port",
+            "8080",
+            "--workers",
+            "4",
+            "--simulated-work-ms",
+            "0",
+            "--max-connections",
+            "2",
+        ]))
+        .unwrap()
+        .config;
+        assert_eq!(config.addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.pool.workers, 4);
+        assert_eq!(config.work, Workload::Sleep(Duration::ZERO));
+        assert_eq!(config.max_connections, 2);
+
+        // an ephemeral port is fine, no workers isn't
+        assert_eq!(
+            Settings::from_options(&args(&["--port", "0"]))
+                .unwrap()
+                .config
+                .addr
+                .port(),
+            0
+        );
+        for bad in [
+            &["--bind", "localhost:3000"][..],
+            &["--workers", "0"],
+            &["--port"],
+        ] {
+            assert!(Settings::from_options(&args(bad)).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = testing::temp_file("port = 8080\nworkers = 4\n");
+        let path = path.to_str().unwrap();
+
+        let config = Settings::from_options(&args(&["--workers", "8", "--config", path]))
+            .unwrap()
+            .config;
+        assert_eq!(config.addr.port(), 8080);
+        assert_eq!(config.pool.workers, 8);
+
+        let e = Settings::from_options(&args(&["--config", "/nonexistent"])).unwrap_err();
+        assert!(e.starts_with("Couldn't read /nonexistent"), "{e}");
+    }
+
+    #[test]
+    fn only_takes_where_to_listen_when_asked() {
+        let path = testing::temp_file("port = 8080\n");
+        let path = path.to_str().unwrap();
+        let settings =
+            Settings::from_listen_options(&args(&["--bind", "0.0.0.0", "--config", path])).unwrap();
+        assert_eq!(settings.config.addr, "0.0.0.0:8080".parse().unwrap());
+
+        // anything else is refused, even set to what it would have been anyway
+        for option in ["--max-connections", "--listen", "--route"] {
+            let e = Settings::from_listen_options(&args(&[option, "1024"])).unwrap_err();
+            assert!(e.starts_with(&format!("{option} can't be set")), "{e}");
+        }
+        let path = testing::temp_file("port = 8080\nworkers = 16\n");
+        let path = path.to_str().unwrap();
+        let e = Settings::from_listen_options(&args(&["--config", path])).unwrap_err();
+        assert!(
+            e.starts_with(&format!("{path}:2: workers can't be set")),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn takes_listeners_and_routes_more_than_once() {
+        let path = testing::temp_file(
+            "listen = 127.0.0.1:3000\n\
+             listen = [::1]:3000\n\
+             route = /health ok\n\
+             route = /version 1.0\n\
+             static = /assets /\n",
+        );
+        let path = path.to_str().unwrap();
+        let settings = Settings::from_options(&args(&["--config", path])).unwrap();
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:3000".parse().unwrap(),
+            "[::1]:3000".parse().unwrap(),
+        ];
+        assert_eq!(settings.addrs(), addrs);
+        let mut routes = Routes::default();
+        routes.add("route", Some("/health ok")).unwrap();
+        routes.add("route", Some("/version 1.0")).unwrap();
+        routes.add("static", Some("/assets /")).unwrap();
+        assert_eq!(settings.routes, routes);
+
+        // flags for a list replace the file's, and leave the other lists alone
+        let settings = Settings::from_options(&args(&[
+            "--config",
+            path,
+            "--listen",
+            "0.0.0.0:8080",
+            "--listen",
+            "0.0.0.0:8081",
+            "--route",
+            "/health fine",
+        ]))
+        .unwrap();
+        let addrs: Vec<SocketAddr> = vec![
+            "0.0.0.0:8080".parse().unwrap(),
+            "0.0.0.0:8081".parse().unwrap(),
+        ];
+        assert_eq!(settings.addrs(), addrs);
+        let mut routes = Routes::default();
+        routes.add("route", Some("/health fine")).unwrap();
+        routes.add("static", Some("/assets /")).unwrap();
+        assert_eq!(settings.routes, routes);
+
+        // without any, it's the one address bind and port make up
+        let settings = Settings::from_options(&args(&["--port", "8080"])).unwrap();
+        assert_eq!(settings.addrs(), vec![settings.config.addr]);
+    }
+}
@@ -0,0 +1,61 @@
+// Why a connection didn't make it to the end of its response.
+// Clients hanging up or resetting the connection is business as usual for a server,
+// so we tell those apart from failures on our side.
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    // the client went away or misbehaved
+    Client(io::Error),
+    // something went wrong on our end
+    Server(io::Error),
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut => ConnectionError::Client(e),
+            _ => ConnectionError::Server(e),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Client(e) => write!(f, "client error: {e}"),
+            ConnectionError::Server(e) => write!(f, "server error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Client(e) | ConnectionError::Server(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blames_the_right_side() {
+        for errno in [libc::ECONNRESET, libc::EPIPE, libc::ETIMEDOUT] {
+            let e = ConnectionError::from(io::Error::from_raw_os_error(errno));
+            assert!(matches!(e, ConnectionError::Client(_)), "{e}");
+        }
+        for errno in [libc::EBADF, libc::ENOMEM] {
+            let e = ConnectionError::from(io::Error::from_raw_os_error(errno));
+            assert!(matches!(e, ConnectionError::Server(_)), "{e}");
+        }
+    }
+}
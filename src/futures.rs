@@ -1,6 +1,16 @@
+// The runtime from the post's futures chapter, all in one file, as the post has it. That
+// includes having no graceful shutdown: `Server::shutdown` doesn't reach it, and on the command
+// line SIGINT and SIGTERM end the process straight away, connections and all. Nor does it have
+// timeouts, so a client that never finishes its request keeps its connection open for good.
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::handler::SharedHandler;
+use crate::limit::{InFlight, InFlightGuard};
+use crate::server;
 use mio::event::Source;
 use mio::net::{TcpListener, TcpStream};
-use std::sync::Once;
+use std::sync::OnceLock;
+use std::thread::sleep;
 use std::{
     cell::RefCell,
     collections::{HashMap, VecDeque},
@@ -104,7 +114,9 @@ impl Scheduler {
         loop {
             loop {
                 // pop a runnable task off the queue
-                let Some(task) = self.runnable.lock().unwrap().pop_front() else { break };
+                let Some(task) = self.runnable.lock().unwrap().pop_front() else {
+                    break;
+                };
                 let t2 = task.clone();
 
                 // create a waker that pushes the task back on
@@ -129,54 +141,89 @@ impl Scheduler {
 thread_local! {
     static REACTOR: RefCell<Reactor> = RefCell::new(Reactor::new());
 }
-static SCHEDULER_INIT: Once = Once::new();
-static mut SCHEDULER: Option<Scheduler> = None;
+static SCHEDULER: OnceLock<Scheduler> = OnceLock::new();
 
 fn get_scheduler() -> &'static Scheduler {
-    unsafe {
-        SCHEDULER_INIT.call_once(|| {
-            SCHEDULER = Some(Scheduler::new());
-        });
-        SCHEDULER.as_ref().unwrap()
-    }
+    SCHEDULER.get_or_init(Scheduler::new)
 }
 
-fn main() {
-    get_scheduler().spawn(Main::Start);
+// There's only one scheduler, so this can only be called once per process.
+// None of the options apply to this runtime.
+pub fn serve(listener: std::net::TcpListener, _config: Config, handler: SharedHandler) {
+    listener.set_nonblocking(true).unwrap();
+    let listener = TcpListener::from_std(listener);
+    let in_flight = InFlight::default();
+    server::ready(&in_flight);
+    get_scheduler().spawn(Main::Start {
+        listener: Some(listener),
+        handler,
+        in_flight,
+    });
     get_scheduler().run();
 }
 
 // main task: accept loop
 enum Main {
-    Start,
-    Accept { listener: TcpListener },
+    Start {
+        listener: Option<TcpListener>,
+        handler: SharedHandler,
+        in_flight: InFlight,
+    },
+    Accept {
+        listener: TcpListener,
+        handler: SharedHandler,
+        in_flight: InFlight,
+    },
 }
 
 impl Future for Main {
     type Output = ();
 
     fn poll(&mut self, waker: Waker) -> Option<()> {
-        if let Main::Start = self {
-            let mut listener = TcpListener::bind("127.0.0.1:3000".parse().unwrap()).unwrap();
+        if let Main::Start {
+            listener,
+            handler,
+            in_flight,
+        } = self
+        {
+            let mut listener = listener.take().unwrap();
 
             REACTOR.with(|reactor| {
                 reactor.borrow_mut().add(&mut listener, waker);
             });
 
-            *self = Main::Accept { listener };
+            *self = Main::Accept {
+                listener,
+                handler: handler.clone(),
+                in_flight: in_flight.clone(),
+            };
         }
 
-        if let Main::Accept { listener } = self {
-            match listener.accept() {
-                Ok((connection, _)) => {
-                    // ...
-                    get_scheduler().spawn(Handler {
-                        connection,
-                        state: HandlerState::Start,
-                    });
+        if let Main::Accept {
+            listener,
+            handler,
+            in_flight,
+        } = self
+        {
+            // we're only woken when new connections arrive, so take all of them
+            loop {
+                match listener.accept() {
+                    Ok((connection, _)) => {
+                        get_scheduler().spawn(Handler {
+                            connection,
+                            handler: handler.clone(),
+                            state: HandlerState::Start,
+                            _in_flight: in_flight.start(),
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                    // out of fds, say: there's only the one thread, so everything waits
+                    Err(e) => {
+                        if accept::recover(e) == Recovery::Backoff {
+                            sleep(accept::BACKOFF);
+                        }
+                    }
                 }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
-                Err(e) => panic!("{e}"),
             }
         }
 
@@ -187,19 +234,16 @@ impl Future for Main {
 // handler task: handles every connection
 struct Handler {
     connection: TcpStream,
+    handler: SharedHandler,
     state: HandlerState,
+    _in_flight: InFlightGuard,
 }
 
+#[allow(clippy::large_enum_variant)]
 enum HandlerState {
     Start,
-    Read {
-        request: [u8; 1024],
-        read: usize,
-    },
-    Write {
-        response: &'static [u8],
-        written: usize,
-    },
+    Read { request: [u8; 1024], read: usize },
+    Write { response: Vec<u8>, written: usize },
     Flush,
 }
 
@@ -223,11 +267,14 @@ impl Future for Handler {
                 match self.connection.read(&mut request[*read..]) {
                     Ok(0) => {
                         println!("client disconnected unexpectedly");
-                        return Some(());
+                        return self.finish();
                     }
                     Ok(n) => *read += n,
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
-                    Err(e) => panic!("{e}"),
+                    Err(e) => {
+                        println!("failed to handle connection: {e}");
+                        return self.finish();
+                    }
                 }
 
                 // did we reach the end of the request?
@@ -238,19 +285,14 @@ impl Future for Handler {
             }
 
             // we're done, print the request
-            let request = String::from_utf8_lossy(&request[..*read]);
-            println!("{}", request);
+            let request = &request[..*read];
+            println!("{}", String::from_utf8_lossy(request));
 
             // and move into the write state
-            let response = concat!(
-                "HTTP/1.1 200 OK\r\n",
-                "Content-Length: 13\n",
-                "Connection: close\r\n\r\n",
-                "Hello world!\n"
-            );
+            let response = self.handler.respond(request);
 
             self.state = HandlerState::Write {
-                response: response.as_bytes(),
+                response,
                 written: 0,
             };
         }
@@ -258,11 +300,14 @@ impl Future for Handler {
         if let HandlerState::Write { response, written } = &mut self.state {
             loop {
                 match self.connection.write(&response[*written..]) {
-                    Ok(0) => return Some(()),
+                    Ok(0) => return self.finish(),
                     Ok(n) => *written += n,
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
                     // some other error occurred
-                    Err(e) => panic!("encountered IO error: {e}"),
+                    Err(e) => {
+                        println!("failed to handle connection: {e}");
+                        return self.finish();
+                    }
                 }
                 // have we written the entire response?
                 if *written == response.len() {
@@ -276,14 +321,24 @@ impl Future for Handler {
             match self.connection.flush() {
                 Ok(_) => {}
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None, // 👈
-                Err(e) => panic!("{e}"),
+                Err(e) => {
+                    println!("failed to handle connection: {e}");
+                    return self.finish();
+                }
             }
         }
 
+        self.finish()
+    }
+}
+
+impl Handler {
+    // The reactor holds on to the task through its waker, so the connection is only dropped,
+    // and closed, once it's taken out again.
+    fn finish(&mut self) -> Option<()> {
         REACTOR.with(|reactor| {
             reactor.borrow_mut().remove(&mut self.connection);
         });
-
         Some(())
     }
 }
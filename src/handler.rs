@@ -0,0 +1,135 @@
+// What the servers answer. Each version reads a request up to the blank line that ends its
+// headers, however it goes about that, then asks the handler what to send back.
+//
+// The versions that give a connection a thread of its own all handle it the same way, with
+// blocking reads and writes, so that lives here too.
+use crate::error::ConnectionError;
+use crate::timeout::{self, Timeouts};
+use crate::workload::Workload;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub trait Handler: Send + Sync + 'static {
+    // The bytes to send in reply to `request`, which is everything up to and including the
+    // blank line after the headers. The connection is closed once they're written.
+    fn respond(&self, request: &[u8]) -> Vec<u8>;
+}
+
+// Shared by every connection, and every thread, of a server.
+pub type SharedHandler = Arc<dyn Handler>;
+
+impl<F> Handler for F
+where
+    F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+{
+    fn respond(&self, request: &[u8]) -> Vec<u8> {
+        self(request)
+    }
+}
+
+// "Hello World!" in HTTP, whatever the request.
+pub struct HelloWorld;
+
+pub const HELLO_WORLD: &[u8] = concat!(
+    "HTTP/1.1 200 OK\r\n",
+    "Content-Length: 13\n",
+    "Connection: close\r\n\r\n",
+    "Hello world!\n"
+)
+.as_bytes();
+
+impl Handler for HelloWorld {
+    fn respond(&self, _request: &[u8]) -> Vec<u8> {
+        HELLO_WORLD.to_vec()
+    }
+}
+
+// Sent to clients whose request is bigger than the kilobyte we read requests into.
+pub const REQUEST_TOO_LARGE: &[u8] = concat!(
+    "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+    "Content-Length: 0\r\n",
+    "Connection: close\r\n\r\n"
+)
+.as_bytes();
+
+// Turn away a client whose request has filled the buffer without ending.
+pub fn request_too_large(connection: &mut impl Write) -> ConnectionError {
+    // best effort, the client may still be sending rather than reading
+    let _ = connection.write(REQUEST_TOO_LARGE);
+    ConnectionError::Client(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "request too large",
+    ))
+}
+
+// Read a request from `connection`, do the work and write the response, blocking the thread
+// throughout.
+pub fn handle_blocking(
+    mut connection: TcpStream,
+    timeouts: &Timeouts,
+    work: Workload,
+    handler: &dyn Handler,
+) -> Result<(), ConnectionError> {
+    let mut read = 0;
+    let mut request = [0u8; 1024];
+    // the request has to start within the idle timeout, then gets the header timeout to finish
+    let mut deadline = Instant::now() + timeouts.idle;
+
+    loop {
+        // try reading from the stream, for as long as the client has left
+        let num_bytes = match timeout::read_before(&mut connection, &mut request[read..], deadline)
+        {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(timeout::request_timed_out(&mut connection, read));
+            }
+            result => result?,
+        };
+
+        // the client disconnected
+        if num_bytes == 0 {
+            println!("client disconnected unexpectedly");
+            return Ok(());
+        }
+
+        if read == 0 {
+            deadline = Instant::now() + timeouts.header;
+        }
+        // keep track of how many bytes we've read
+        read += num_bytes;
+
+        // have we reached the end of the request?
+        if read >= 4 && request.get(read - 4..read) == Some(b"\r\n\r\n") {
+            break;
+        }
+        // or run out of room for it?
+        if read == request.len() {
+            return Err(request_too_large(&mut connection));
+        }
+    }
+
+    // println!("{}", String::from_utf8_lossy(&request[..read]));
+    work.run();
+    let response = handler.respond(&request[..read]);
+
+    let mut written = 0;
+    let deadline = Instant::now() + timeouts.write;
+
+    // until we've written the whole response
+    while written < response.len() {
+        // write the remaining response bytes, unless the client is too slow to take them
+        let num_bytes = timeout::write_before(&mut connection, &response[written..], deadline)?;
+
+        // the client disconnected
+        if num_bytes == 0 {
+            println!("client disconnected unexpectedly");
+            return Ok(());
+        }
+
+        written += num_bytes;
+    }
+
+    connection.flush()?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+// The servers from the post, as a library: each version, the settings they share and what
+// they answer with. Pick a way of handling connections with a `ServerStrategy`, give it a
+// `Handler`, and start it on a `Server` of its own:
+//
+//     let config = Config { addr: "127.0.0.1:0".parse().unwrap(), ..Config::default() };
+//     let server = Arc::new(Epoll).start(config, Arc::new(HelloWorld))?;
+//     println!("listening on {}", server.addr());
+//
+// The library leaves the process's signals alone. Stop a server with `server.shutdown()` and
+// give it new settings with `server.reload(config)`; the command line does both on SIGTERM and
+// SIGHUP. `Epoll` doesn't reload: it keeps the settings it started with.
+mod accept;
+mod backoff;
+pub mod bench;
+mod busted_polling;
+pub mod compare;
+pub mod config;
+mod error;
+mod r#final;
+mod futures;
+pub mod handler;
+mod limit;
+mod mio;
+mod multithread;
+mod nonblocking;
+mod nonblocking_spin;
+mod procfs;
+pub mod routes;
+mod runtime;
+pub mod server;
+mod shutdown;
+mod simple;
+pub mod strategy;
+#[cfg(test)]
+mod testing;
+mod threadpool;
+mod timeout;
+pub mod workload;
+
+pub use backoff::SpinMode;
+pub use config::{Config, Settings};
+pub use handler::{Handler, HelloWorld, SharedHandler};
+pub use limit::InFlight;
+pub use routes::{Router, Routes};
+pub use server::Server;
+pub use strategy::{
+    Blocking, CustomAsync, Epoll, ServerStrategy, Spin, StateMachine, ThreadPerConnection,
+    ThreadPool,
+};
+pub use threadpool::{PoolConfig, QueuePolicy};
+pub use timeout::Timeouts;
+pub use workload::Workload;
+
+use std::net::TcpListener;
+
+// A version of the server: what it's called on the command line, and how to run it on a
+// listener that's already bound.
+pub struct Variant {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub serve: fn(TcpListener, Config, SharedHandler),
+    // Whether it takes the settings beyond where to listen. The ones that don't are the post's
+    // code as written, with no timeouts, connection limit, simulated work or graceful shutdown:
+    // `Server::shutdown` does nothing for them, and they only stop with the process.
+    pub tunable: bool,
+}
+
+// Every version of the server, roughly in the order they come up in the post.
+pub const VARIANTS: &[Variant] = &[
+    Variant {
+        name: "simple",
+        about: "one connection at a time, with blocking I/O",
+        serve: simple::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "multithread",
+        about: "a thread per connection",
+        serve: multithread::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "threadpool",
+        about: "a fixed pool of threads fed by a bounded queue",
+        serve: threadpool::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "nonblocking_spin",
+        about: "non-blocking I/O, spinning in a thread per connection",
+        serve: nonblocking_spin::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "nonblocking",
+        about: "non-blocking I/O, every connection on one thread",
+        serve: nonblocking::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "busted_polling",
+        about: "an epoll event loop, with the work blocking it",
+        serve: busted_polling::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "mio",
+        about: "futures on our own runtime, built on mio",
+        serve: mio::serve,
+        tunable: true,
+    },
+    Variant {
+        name: "futures",
+        about: "the runtime from the futures chapter, all in one file",
+        serve: futures::serve,
+        tunable: false,
+    },
+    Variant {
+        name: "final",
+        about: "the same, as it stands at the end of the post",
+        serve: r#final::serve,
+        tunable: false,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // For each check in `testing`, a test running it against every version it applies to, named
+    // `tests::<test>::<version>`.
+    macro_rules! check_variants {
+        ($($test:ident => $check:ident: $($variant:ident),+;)+) => {$(
+            mod $test {
+                use crate::testing;
+                $(
+                    #[test]
+                    fn $variant() {
+                        testing::$check(testing::test_name!($variant), crate::$variant::serve);
+                    }
+                )+
+            }
+        )+};
+    }
+
+    check_variants! {
+        // simple only ever has the one connection
+        survives_running_out_of_fds => check_fd_exhaustion:
+            multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        // simple only ever has the one connection
+        leaves_clients_waiting_at_the_connection_limit => check_connection_limit:
+            multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        serves_many_simultaneous_clients => check_simultaneous_clients:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        survives_connection_resets => check_survives_resets:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        // futures and final don't, they're the post's code as written
+        shuts_down_gracefully => check_graceful_shutdown:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        idles_without_spinning => check_idle_cpu:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        // futures and final wait as long as a client likes, as the post's code does
+        gives_up_on_slow_clients => check_timeouts:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, busted_polling, mio;
+        // busted_polling keeps the settings it started with
+        reloads_settings_on_sighup => check_reload:
+            simple, multithread, threadpool, nonblocking_spin, nonblocking, mio;
+    }
+
+    #[test]
+    fn every_variant_answers_a_request() {
+        // all running at once, each on a port of its own
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+        let servers: Vec<_> = VARIANTS
+            .iter()
+            .map(|variant| {
+                let serve = variant.serve;
+                Server::start(
+                    move |listener, config| serve(listener, config, testing::hello_world()),
+                    config,
+                )
+                .unwrap()
+            })
+            .collect();
+        for (variant, server) in VARIANTS.iter().zip(&servers) {
+            let response = testing::simultaneous_requests(server.addr(), 1).remove(0);
+            assert!(
+                response.ends_with("Hello world!\n"),
+                "{}: {response:?}",
+                variant.name
+            );
+        }
+    }
+}
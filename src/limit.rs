@@ -0,0 +1,123 @@
+// Keeping track of, and a cap on, how many connections a server handles at once.
+// At the cap we stop accepting, so new clients wait in the listener's backlog (and past that
+// get refused by the kernel) instead of each costing us another thread or buffer.
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+// How often servers that can't be told when a connection finishes check back, either to see if
+// there's room or, waiting for room, whether they've been asked to shut down.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Limit {
+    max: usize,
+    full: bool,
+}
+
+impl Limit {
+    pub fn new(max: usize) -> Self {
+        assert!(max > 0, "the connection limit must be at least 1");
+        Limit { max, full: false }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // Change the cap, which applies from the next check.
+    pub fn set_max(&mut self, max: usize) {
+        assert!(max > 0, "the connection limit must be at least 1");
+        self.max = max;
+    }
+
+    // Whether we can take another connection on top of the `current` ones.
+    // Says so when we hit the cap, so it's clear why clients are waiting.
+    pub fn has_room(&mut self, current: usize) -> bool {
+        let full = current >= self.max;
+        if full && !self.full {
+            println!(
+                "at the limit of {} connections, leaving new ones in the backlog",
+                self.max
+            );
+        }
+        self.full = full;
+        !full
+    }
+
+    // Whether we were at the cap the last time we checked.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+}
+
+// Counts the connections a server is handling, so we can hold off accepting at the cap and wait
+// for them to finish before exiting. `Server::in_flight` hands it out to anyone else who asks.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<(Mutex<usize>, Condvar)>);
+
+impl InFlight {
+    // Count a connection until the returned guard is dropped.
+    pub fn start(&self) -> InFlightGuard {
+        *self.0 .0.lock().unwrap() += 1;
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        *self.0 .0.lock().unwrap()
+    }
+
+    // Wait for every connection to finish, or the deadline to pass.
+    // Returns how many are still going.
+    pub fn wait(&self, deadline: Instant) -> usize {
+        self.wait_below(1, deadline)
+    }
+
+    // Wait for there to be fewer than `max` connections, or the deadline to pass.
+    // Returns how many are going.
+    pub fn wait_below(&self, max: usize, deadline: Instant) -> usize {
+        let (count, finished) = &*self.0;
+        let mut count = count.lock().unwrap();
+        while *count >= max {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            count = finished.wait_timeout(count, timeout).unwrap().0;
+        }
+        *count
+    }
+}
+
+pub struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let (count, finished) = &*(self.0).0;
+        *count.lock().unwrap() -= 1;
+        finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::spawn;
+
+    #[test]
+    fn counts_connections_until_they_finish() {
+        let in_flight = InFlight::default();
+        let guards: Vec<_> = (0..3).map(|_| in_flight.start()).collect();
+        assert_eq!(in_flight.count(), 3);
+
+        let mut limit = Limit::new(3);
+        assert!(!limit.has_room(in_flight.count()));
+        assert!(limit.is_full());
+
+        spawn(move || drop(guards));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert!(in_flight.wait_below(3, deadline) < 3);
+        assert_eq!(in_flight.wait(deadline), 0);
+        assert!(limit.has_room(in_flight.count()));
+    }
+}
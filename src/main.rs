@@ -1,26 +1,211 @@
-mod busted_polling;
-mod mio;
-mod multithread;
-mod nonblocking;
-mod nonblocking_spin;
-mod simple;
-
-fn main() {
+use learning_async_rust_web_servers::{
+    bench, compare, Config, Router, Server, Settings, SharedHandler, VARIANTS,
+};
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const OPTIONS: &str = "\
+Options:
+    --config <PATH>              read settings from a file of `option = value` lines,
+                                 named like these options, which override it; SIGHUP
+                                 reads it again for new connections
+    --bind <ADDRESS>             IP address to listen on [default: 127.0.0.1]
+    --port <PORT>                port to listen on, 0 for any free one, printed once
+                                 it's listening [default: 3000]
+    --listen <ADDRESS:PORT>      an address to listen on, in place of --bind and --port;
+                                 give it more than once to listen on each of them
+    --route \"<PATH> <TEXT>\"      answer GETs for PATH with TEXT, as in --route \"/health ok\";
+                                 give it more than once for more routes. With no routes
+                                 or static roots, every request gets hello world
+    --static \"<PREFIX> <DIR>\"    answer GETs under PREFIX with files from DIR
+    --workers <N>                threads in the pool (threadpool) [default: 16]
+    --queue-size <N>             connections waiting for a worker (threadpool) [default: 64]
+    --queue-policy <POLICY>      when the queue is full: block, reject or drop-oldest
+                                 (threadpool) [default: block]
+    --workload <PROFILE>         what each request pretends to do: sleep:MS, random:MIN-MAX,
+                                 cpu:ITERATIONS, alloc:BYTES or read:BYTES [default: sleep:10]
+    --simulated-work-ms <MS>     the same as --workload sleep:MS
+    --max-connections <N>        connections handled at once, counting any queued for a
+                                 worker [default: 1024]
+    --spin <MODE>                busy or adaptive, how the non-blocking versions wait
+                                 [default: adaptive]
+    --idle-timeout-ms <MS>       time a client gets to start its request [default: 10000]
+    --header-timeout-ms <MS>     time a client gets to finish its request [default: 10000]
+    --write-timeout-ms <MS>      time a client gets to take the response [default: 10000]
+    -h, --help                   print this help
+";
+
+fn usage() -> String {
+    let mut usage = format!(
+        "Usage: {} <VERSION> [OPTIONS]\n       {0} bench [OPTIONS]\n       {0} compare [OPTIONS]\n\nVersions:\n",
+        env!("CARGO_PKG_NAME")
+    );
+    let list = |tunable| {
+        VARIANTS
+            .iter()
+            .filter(|variant| variant.tunable == tunable)
+            .map(|variant| format!("    {:<29}{}\n", variant.name, variant.about))
+            .collect::<String>()
+    };
+    usage += &list(true);
+    usage +=
+        "\nAs written in the post, taking no options but --bind, --port and --config, and with\n\
+              no timeouts, connection limit, simulated work or graceful shutdown:\n";
+    usage += &list(false);
+    usage
+        + "\n"
+        + OPTIONS
+        + "\nSee bench --help and compare --help for the load generator's options.\n"
+}
+
+fn main() -> ExitCode {
     let args: Vec<String> = std::env::args().collect();
 
+    let help = args.iter().any(|arg| arg == "-h" || arg == "--help");
+    let commands = [
+        (
+            "bench",
+            bench::USAGE,
+            bench::main as fn(&[String]) -> ExitCode,
+        ),
+        ("compare", compare::USAGE, compare::main),
+    ];
+    for (name, usage, run) in commands {
+        if args.get(1).is_some_and(|command| command == name) {
+            if help {
+                print!("{usage}");
+                return ExitCode::SUCCESS;
+            }
+            return run(&args[2..]);
+        }
+    }
+
+    if help {
+        print!("{}", usage());
+        return ExitCode::SUCCESS;
+    }
     if args.len() < 2 {
-        println!("Please specify a version of the webserver to run.");
-        return;
+        eprint!(
+            "Please specify a version of the webserver to run.\n\n{}",
+            usage()
+        );
+        return ExitCode::from(2);
     }
 
     let version = args[1].as_str();
-    match version {
-        "simple" => simple::main(),
-        "multithread" => multithread::main(),
-        "mio" => mio::main(),
-        "nonblocking_spin" => nonblocking_spin::main(),
-        "nonblocking" => nonblocking::main(),
-        "busted_polling" => busted_polling::main(),
-        _ => println!("Invalid version specified: {:}.", version),
+    let Some(variant) = VARIANTS.iter().find(|variant| variant.name == version) else {
+        eprintln!("Invalid version specified: {version}. See --help for the list.");
+        return ExitCode::from(2);
+    };
+
+    let settings = if variant.tunable {
+        Settings::from_options(&args[2..])
+    } else {
+        Settings::from_listen_options(&args[2..])
+    };
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}. See --help for the options.");
+            return ExitCode::from(2);
+        }
+    };
+
+    // from here on, and in the servers' threads, these wait for `sigtimedwait` below. The
+    // untunable variants have no graceful shutdown, so signals do what they always do to them.
+    let signals = variant.tunable.then(block_signals);
+
+    // a server for each address, all answering from the same routes
+    let router = Arc::new(Router::new(settings.routes.clone()));
+    let mut servers = Vec::new();
+    for addr in settings.addrs() {
+        let serve = variant.serve;
+        let handler: SharedHandler = router.clone();
+        let config = Config {
+            addr,
+            ..settings.config
+        };
+        let server = match Server::start(
+            move |listener, config| serve(listener, config, handler),
+            config,
+        ) {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("Couldn't start on {addr}: {e}.");
+                return ExitCode::FAILURE;
+            }
+        };
+        // once this is printed, the server's set up and taking connections, so a parent process
+        // that started us on port 0 can wait for this line to find out where
+        println!("listening on {}", server.addr());
+        servers.push((addr, server));
+    }
+
+    // SIGINT or SIGTERM stops the servers, and a second one stops waiting for them. SIGHUP
+    // reads the file and flags again for the connections and requests after it.
+    let mut stopping = false;
+    let check_every = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 100_000_000,
+    };
+    let running =
+        |servers: &[(SocketAddr, Server)]| servers.iter().any(|(_, server)| !server.is_finished());
+    while let Some(signals) = signals.filter(|_| running(&servers)) {
+        // wake now and then in case a server stops by itself, by panicking
+        match unsafe { libc::sigtimedwait(&signals, std::ptr::null_mut(), &check_every) } {
+            libc::SIGHUP => match Settings::from_options(&args[2..]) {
+                // each keeps the address it started with, and requests already underway keep
+                // the routes they started with
+                Ok(settings) => {
+                    let addrs: Vec<SocketAddr> = servers.iter().map(|(addr, _)| *addr).collect();
+                    if settings.addrs() != addrs {
+                        println!("still listening where we were, moving needs a restart");
+                    }
+                    router.set(settings.routes);
+                    for (addr, server) in &servers {
+                        server.reload(Config {
+                            addr: *addr,
+                            ..settings.config
+                        });
+                    }
+                }
+                Err(e) => println!("keeping the current configuration: {e}"),
+            },
+            libc::SIGINT | libc::SIGTERM if stopping => {
+                println!("asked again, not waiting for connections to finish");
+                return ExitCode::FAILURE;
+            }
+            libc::SIGINT | libc::SIGTERM => {
+                stopping = true;
+                for (_, server) in &servers {
+                    server.shutdown();
+                }
+            }
+            // timed out
+            _ => {}
+        }
+    }
+    // the panics have already been reported
+    let mut code = ExitCode::SUCCESS;
+    for (_, server) in servers {
+        if server.join().is_err() {
+            code = ExitCode::FAILURE;
+        }
+    }
+    code
+}
+
+// Block SIGINT, SIGTERM and SIGHUP in this thread and any it starts, so they're only delivered
+// by waiting for them, returning the set to wait for.
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut signals, signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
     }
 }
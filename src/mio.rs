@@ -1,184 +1,158 @@
-use mio::event::Source;
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::error::ConnectionError;
+use crate::handler::{self, SharedHandler};
+use crate::limit::{self, InFlight, InFlightGuard, Limit};
+use crate::runtime::{self, Future, Handle, Runtime, Timer, Waker};
+use crate::server;
+use crate::shutdown::{self, Signals};
+use crate::timeout::{self, Timeouts};
+use crate::workload::Workload;
 use mio::net::{TcpListener, TcpStream};
-use std::sync::Once;
-use std::{
-    cell::RefCell,
-    collections::{HashMap, VecDeque},
-    io::{self, Read, Write},
-    os::fd::AsRawFd,
-    sync::{Arc, Mutex},
-};
-
-#[derive(Clone)]
-struct Waker(Arc<dyn Fn() + Send + Sync>);
-
-impl Waker {
-    fn wake(&self) {
-        (self.0)()
-    }
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+pub fn serve(listener: std::net::TcpListener, config: Config, handler: SharedHandler) {
+    listener.set_nonblocking(true).unwrap();
+    let listener = TcpListener::from_std(listener);
+    let signals = shutdown::current().unwrap();
+
+    let runtime = Runtime::new().unwrap();
+    let in_flight = InFlight::default();
+    server::ready(&in_flight);
+
+    // returns once we've been asked to shut down, dropping any connections that didn't finish
+    runtime.block_on(Main {
+        listener: Some(listener),
+        signals,
+        in_flight,
+        limit: Limit::new(config.max_connections),
+        config,
+        handler,
+        state: MainState::Start,
+        check: Deadline {
+            at: Instant::now(),
+            timer: None,
+        },
+    });
 }
 
-trait Future {
-    type Output;
-
-    fn poll(&mut self, waker: Waker) -> Option<Self::Output>;
+// main task: accept loop
+struct Main {
+    // closed once we start shutting down
+    listener: Option<TcpListener>,
+    signals: Signals,
+    in_flight: InFlight,
+    limit: Limit,
+    // what new connections get, which `Server::reload` can change
+    config: Config,
+    handler: SharedHandler,
+    state: MainState,
+    // when to look again at what doesn't wake us by itself: connections finishing, or the end
+    // of a pause
+    check: Deadline,
 }
 
-use mio::{Events, Poll, Token};
-
-struct Reactor {
-    poll: Poll,
-    tasks: RefCell<HashMap<Token, Waker>>,
+enum MainState {
+    Start,
+    Accept,
+    // we ran out of resources, so stop accepting to let existing connections finish
+    Paused { until: Instant },
+    // we're shutting down, waiting for the connections we have to finish
+    Draining { deadline: Instant },
 }
 
-impl Reactor {
-    pub fn new() -> Reactor {
-        Reactor {
-            poll: Poll::new().unwrap(),
-            tasks: RefCell::new(HashMap::new()),
-        }
-    }
-
-    pub fn add<S: Source + AsRawFd>(&self, source: &mut S, waker: Waker) {
-        let token = Token(source.as_raw_fd() as usize); // Assigning a token using raw fd
-        self.poll
-            .registry()
-            .register(
-                source,
-                token,
-                mio::Interest::READABLE | mio::Interest::WRITABLE,
-            )
-            .unwrap();
-        self.tasks.borrow_mut().insert(token, waker);
-    }
+impl Future for Main {
+    type Output = ();
 
-    pub fn remove<S: Source + AsRawFd>(&self, source: &mut S) {
-        let token = Token(source.as_raw_fd() as usize);
-        if let Err(e) = self.poll.registry().deregister(source) {
-            eprintln!(
-                "Failed to deregister source with token {:?} due to error {:?}",
-                token, e
-            ); // or handle it appropriately
+    fn poll(&mut self, waker: Waker) -> Option<()> {
+        if let MainState::Start = self.state {
+            let handle = Handle::current();
+            let reactor = handle.reactor();
+            reactor
+                .add(self.listener.as_mut().unwrap(), waker.clone())
+                .unwrap();
+            reactor.add(&mut self.signals, waker.clone()).unwrap();
+
+            self.state = MainState::Accept;
         }
-        self.tasks.borrow_mut().remove(&token);
-    }
 
-    // Drive tasks forward, blocking forever until an event arrives.
-    pub fn wait(&mut self) {
-        let mut events = Events::with_capacity(1024);
-
-        self.poll.poll(&mut events, None).unwrap();
-
-        for event in events.iter() {
-            let token = event.token();
-
-            // wake the task
-            if let Some(waker) = self.tasks.borrow().get(&token) {
-                waker.clone().wake();
+        if self.signals.requested() {
+            if let Some(mut listener) = self.listener.take() {
+                // stop accepting new connections, and give the ones we have time to finish
+                Handle::current().reactor().remove(&mut listener);
+                self.state = MainState::Draining {
+                    deadline: Instant::now() + shutdown::DRAIN_TIMEOUT,
+                };
             }
         }
-    }
-}
 
-type SharedTask = Arc<Mutex<dyn Future<Output = ()> + Send>>;
-
-// The scheduler.
-#[derive(Default)]
-struct Scheduler {
-    runnable: Mutex<VecDeque<SharedTask>>,
-}
-
-impl Scheduler {
-    pub fn new() -> Self {
-        Scheduler {
-            runnable: Mutex::new(VecDeque::new()),
-        }
-    }
-    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
-        self.runnable
-            .lock()
-            .unwrap()
-            .push_back(Arc::new(Mutex::new(task)));
-    }
-    pub fn run(&self) {
-        loop {
-            loop {
-                // pop a runnable task off the queue
-                let Some(task) = self.runnable.lock().unwrap().pop_front() else {
-                    break;
-                };
-                let t2 = task.clone();
-
-                // create a waker that pushes the task back on
-                let wake = Arc::new(move || {
-                    get_scheduler()
-                        .runnable
-                        .lock()
-                        .unwrap()
-                        .push_back(t2.clone());
-                });
-
-                // poll the task
-                task.lock().unwrap().poll(Waker(wake));
+        if let MainState::Draining { deadline } = self.state {
+            let remaining = self.in_flight.count();
+            if remaining == 0 || Instant::now() >= deadline {
+                self.check.clear();
+                shutdown::report(remaining);
+                return Some(());
             }
-
-            // if there are no runnable tasks, block on epoll until something becomes ready
-            REACTOR.with(|reactor| reactor.borrow_mut().wait());
+            // handlers don't tell us when they finish, so check back in a bit
+            let next_check = (Instant::now() + limit::CHECK_INTERVAL).min(deadline);
+            self.check.set(next_check, &waker);
+            return None;
         }
-    }
-}
-
-thread_local! {
-    static REACTOR: RefCell<Reactor> = RefCell::new(Reactor::new());
-}
-static SCHEDULER_INIT: Once = Once::new();
-static mut SCHEDULER: Option<Scheduler> = None;
-
-fn get_scheduler() -> &'static Scheduler {
-    unsafe {
-        SCHEDULER_INIT.call_once(|| {
-            SCHEDULER = Some(Scheduler::new());
-        });
-        SCHEDULER.as_ref().unwrap()
-    }
-}
-
-pub fn main() {
-    get_scheduler().spawn(Main::Start);
-    get_scheduler().run();
-}
 
-// main task: accept loop
-enum Main {
-    Start,
-    Accept { listener: TcpListener },
-}
-
-impl Future for Main {
-    type Output = ();
-
-    fn poll(&mut self, waker: Waker) -> Option<()> {
-        if let Main::Start = self {
-            let mut listener = TcpListener::bind("127.0.0.1:3000".parse().unwrap()).unwrap();
-
-            REACTOR.with(|reactor| {
-                reactor.borrow_mut().add(&mut listener, waker);
-            });
-
-            *self = Main::Accept { listener };
+        if let MainState::Paused { until } = self.state {
+            // we might just be hearing about a new connection, which will have to wait
+            if Instant::now() < until {
+                return None;
+            }
+            self.state = MainState::Accept;
         }
 
-        if let Main::Accept { listener } = self {
-            match listener.accept() {
-                Ok((connection, _)) => {
-                    // ...
-                    get_scheduler().spawn(Handler {
-                        connection,
-                        state: HandlerState::Start,
-                    });
+        if let MainState::Accept = self.state {
+            // the listener is edge-triggered, so we only hear about new connections once:
+            // keep accepting until there are none left
+            loop {
+                if !runtime::consume_budget(&waker) {
+                    return None;
+                }
+                if self.config.reload(&self.signals) {
+                    self.limit.set_max(self.config.max_connections);
+                }
+                // at the limit: leave new connections in the backlog until a handler finishes,
+                // which it won't tell us about, so check back in a bit
+                if !self.limit.has_room(self.in_flight.count()) {
+                    self.check
+                        .set(Instant::now() + limit::CHECK_INTERVAL, &waker);
+                    return None;
+                }
+                let listener = self.listener.as_mut().unwrap();
+                match listener.accept() {
+                    Ok((connection, _)) => {
+                        // ...
+                        Handle::current().spawn(Handler {
+                            connection,
+                            state: HandlerState::Start,
+                            timeouts: self.config.timeouts,
+                            work: self.config.work,
+                            handler: self.handler.clone(),
+                            // the client has the idle timeout to start its request
+                            deadline: Deadline {
+                                at: Instant::now() + self.config.timeouts.idle,
+                                timer: None,
+                            },
+                            _in_flight: self.in_flight.start(),
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                    Err(e) => {
+                        if accept::recover(e) == Recovery::Backoff {
+                            let until = Instant::now() + accept::BACKOFF;
+                            self.check.set(until, &waker);
+                            self.state = MainState::Paused { until };
+                            return None;
+                        }
+                    }
                 }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
-                Err(e) => panic!("{e}"),
             }
         }
 
@@ -190,18 +164,46 @@ impl Future for Main {
 struct Handler {
     connection: TcpStream,
     state: HandlerState,
+    timeouts: Timeouts,
+    // what to pretend to do once the request is in
+    work: Workload,
+    handler: SharedHandler,
+    deadline: Deadline,
+    _in_flight: InFlightGuard,
+}
+
+// When a task has to be woken, and the timer that does it. Setting it again replaces the timer
+// rather than adding another.
+struct Deadline {
+    at: Instant,
+    timer: Option<Timer>,
+}
+
+impl Deadline {
+    fn set(&mut self, at: Instant, waker: &Waker) {
+        self.clear();
+        self.at = at;
+        self.timer = Some(Handle::current().reactor().add_timer(at, waker.clone()));
+    }
+
+    fn clear(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            Handle::current().reactor().remove_timer(timer);
+        }
+    }
+
+    fn passed(&self) -> bool {
+        Instant::now() >= self.at
+    }
 }
 
+#[allow(clippy::large_enum_variant)]
 enum HandlerState {
     Start,
-    Read {
-        request: [u8; 1024],
-        read: usize,
-    },
-    Write {
-        response: &'static [u8],
-        written: usize,
-    },
+    Read { request: [u8; 1024], read: usize },
+    // waiting on simulated work, which ends at the deadline
+    Work { response: Vec<u8> },
+    Write { response: Vec<u8>, written: usize },
     Flush,
 }
 
@@ -210,26 +212,59 @@ impl Future for Handler {
 
     fn poll(&mut self, waker: Waker) -> Option<Self::Output> {
         if let HandlerState::Start = self.state {
-            REACTOR.with(|reactor| {
-                reactor.borrow_mut().add(&mut self.connection, waker);
-            });
+            let registered = Handle::current()
+                .reactor()
+                .add(&mut self.connection, waker.clone());
+            if let Err(e) = registered {
+                println!("failed to handle connection: {}", ConnectionError::from(e));
+                return Some(());
+            }
 
             self.state = HandlerState::Read {
                 request: [0u8; 1024],
                 read: 0,
             };
+            // now we have a waker, make sure we're woken when the idle timeout is up
+            self.deadline.set(self.deadline.at, &waker);
+        }
+
+        // the client has run out of time, whether or not that's why we were woken
+        if self.deadline.passed() && !matches!(self.state, HandlerState::Work { .. }) {
+            let e = match &self.state {
+                HandlerState::Read { read, .. } => {
+                    timeout::request_timed_out(&mut self.connection, *read)
+                }
+                _ => ConnectionError::from(io::Error::from(io::ErrorKind::TimedOut)),
+            };
+            println!("failed to handle connection: {e}");
+            return Some(());
         }
 
         if let HandlerState::Read { request, read } = &mut self.state {
             loop {
+                // don't let one chatty client hog the thread
+                if !runtime::consume_budget(&waker) {
+                    return None;
+                }
                 match self.connection.read(&mut request[*read..]) {
                     Ok(0) => {
                         println!("client disconnected unexpectedly");
                         return Some(());
                     }
-                    Ok(n) => *read += n,
+                    Ok(n) => {
+                        // the request has started, it gets the header timeout to finish
+                        if *read == 0 {
+                            let at = Instant::now() + self.timeouts.header;
+                            self.deadline.set(at, &waker);
+                        }
+                        *read += n;
+                    }
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
-                    Err(e) => panic!("{e}"),
+                    // something went wrong with this connection, give up on it
+                    Err(e) => {
+                        println!("failed to handle connection: {}", ConnectionError::from(e));
+                        return Some(());
+                    }
                 }
 
                 // did we reach the end of the request?
@@ -237,38 +272,57 @@ impl Future for Handler {
                 if read >= 4 && &request[read - 4..read] == b"\r\n\r\n" {
                     break;
                 }
+                // or run out of room for it?
+                if read == request.len() {
+                    let e = handler::request_too_large(&mut self.connection);
+                    println!("failed to handle connection: {e}");
+                    return Some(());
+                }
             }
 
             // we're done, print the request
-            let _request = String::from_utf8_lossy(&request[..*read]);
-            // println!("{}", request);
+            // println!("{}", String::from_utf8_lossy(&request[..*read]));
+            let response = self.handler.respond(&request[..*read]);
+
+            // simulate doing some work: waiting goes on a timer so other connections carry on
+            // meanwhile, anything else holds up the whole thread
+            let delay = self.work.delay().unwrap_or_else(|| {
+                self.work.run();
+                Duration::ZERO
+            });
+            self.state = HandlerState::Work { response };
+            self.deadline.set(Instant::now() + delay, &waker);
+        }
 
-            // and move into the write state
-            let response = concat!(
-                "HTTP/1.1 200 OK\r\n",
-                "Content-Length: 13\n",
-                "Connection: close\r\n\r\n",
-                "Hello world!\n"
-            );
+        if let HandlerState::Work { response } = &mut self.state {
+            if !self.deadline.passed() {
+                return None;
+            }
 
+            // and move into the write state
             self.state = HandlerState::Write {
-                response: response.as_bytes(),
+                response: std::mem::take(response),
                 written: 0,
             };
+            self.deadline
+                .set(Instant::now() + self.timeouts.write, &waker);
         }
 
         if let HandlerState::Write { response, written } = &mut self.state {
-            loop {
+            // until we've written the entire response
+            while *written < response.len() {
+                if !runtime::consume_budget(&waker) {
+                    return None;
+                }
                 match self.connection.write(&response[*written..]) {
                     Ok(0) => return Some(()),
                     Ok(n) => *written += n,
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
                     // some other error occurred
-                    Err(e) => panic!("encountered IO error: {e}"),
-                }
-                // have we written the entire response?
-                if *written == response.len() {
-                    break;
+                    Err(e) => {
+                        println!("failed to handle connection: {}", ConnectionError::from(e));
+                        return Some(());
+                    }
                 }
             }
             self.state = HandlerState::Flush;
@@ -278,14 +332,47 @@ impl Future for Handler {
             match self.connection.flush() {
                 Ok(_) => {}
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None, // 👈
-                Err(e) => panic!("{e}"),
+                Err(e) => println!("failed to handle connection: {}", ConnectionError::from(e)),
             }
         }
 
-        REACTOR.with(|reactor| {
-            reactor.borrow_mut().remove(&mut self.connection);
-        });
-
         Some(())
     }
 }
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        // however the handler finished, the reactor needs to forget about the connection
+        if !matches!(self.state, HandlerState::Start) {
+            Handle::current().reactor().remove(&mut self.connection);
+        }
+        self.deadline.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn works_on_requests_concurrently() {
+        let config = Config {
+            work: Workload::Sleep(Duration::from_millis(200)),
+            ..Config::default()
+        };
+        let addr =
+            testing::spawn_server(move |listener| serve(listener, config, testing::hello_world()));
+
+        // one after the other, these would take two seconds
+        let start = Instant::now();
+        for response in testing::simultaneous_requests(addr, 10) {
+            assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
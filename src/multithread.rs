@@ -1,78 +1,53 @@
 // Uses I/O blocking with multithreading
-use std::io;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::handler::{handle_blocking, SharedHandler};
+use crate::limit::{self, InFlight, Limit};
+use crate::server;
+use crate::shutdown;
+use std::net::TcpListener;
 use std::thread::sleep;
 use std::thread::spawn;
-use std::time::Duration;
+use std::time::Instant;
 
-pub fn main() {
-    let listener = TcpListener::bind("localhost:3000").unwrap();
-    loop {
-        let (connection, _) = listener.accept().unwrap();
+pub fn serve(listener: TcpListener, mut config: Config, handler: SharedHandler) {
+    let signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    let mut limit = Limit::new(config.max_connections);
+    server::ready(&in_flight);
 
-        spawn(|| {
-            if let Err(e) = handle_connection(connection) {
-                println!("failed to handle connection: {e}")
-            }
-        });
-    }
-}
-
-fn handle_connection(mut connection: TcpStream) -> io::Result<()> {
-    let mut read = 0;
-    let mut request = [0u8; 1024];
-
-    loop {
-        // try reading from the stream
-        let num_bytes = connection.read(&mut request[read..])?;
-
-        // the client disconnected
-        if num_bytes == 0 {
-            println!("client disconnected unexpectedly");
-            return Ok(());
-        }
-
-        // keep track of how many bytes we've read
-        read += num_bytes;
-
-        // have we reached the end of the request?
-        if request.get(read - 4..read) == Some(b"\r\n\r\n") {
-            break;
+    while signals.wait_for_connection(&listener).unwrap() {
+        if config.reload(&signals) {
+            limit.set_max(config.max_connections);
         }
-    }
-
-    let _request = String::from_utf8_lossy(&request[..read]);
-    // println!("{request}");
-    sleep(Duration::from_millis(10));
-
-    // "Hello World!" in HTTP
-    let response = concat!(
-        "HTTP/1.1 200 OK\r\n",
-        "Content-Length: 13\n",
-        "Connection: close\r\n\r\n",
-        "Hello world!\n"
-    );
-
-    let mut written = 0;
-
-    loop {
-        // write the remaining response bytes
-        let num_bytes = connection.write(response[written..].as_bytes())?;
-
-        // the client disconnected
-        if num_bytes == 0 {
-            println!("client disconnected unexpectedly");
-            return Ok(());
+        // at the limit: no more threads until one finishes, checking back in case we're asked
+        // to shut down meanwhile
+        if !limit.has_room(in_flight.count()) {
+            in_flight.wait_below(limit.max(), Instant::now() + limit::CHECK_INTERVAL);
+            continue;
         }
 
-        written += num_bytes;
+        let connection = match listener.accept() {
+            Ok((connection, _)) => connection,
+            Err(e) => {
+                if accept::recover(e) == Recovery::Backoff {
+                    sleep(accept::BACKOFF);
+                }
+                continue;
+            }
+        };
 
-        // have we written the whole response yet?
-        if written == response.len() {
-            break;
-        }
+        let guard = in_flight.start();
+        let handler = handler.clone();
+        spawn(move || {
+            if let Err(e) = handle_blocking(connection, &config.timeouts, config.work, &*handler) {
+                println!("failed to handle connection: {e}")
+            }
+            drop(guard);
+        });
     }
 
-    connection.flush()
+    // stop accepting new connections, and give the ones we have time to finish
+    drop(listener);
+    shutdown::report(in_flight.wait(Instant::now() + shutdown::DRAIN_TIMEOUT));
 }
@@ -1,92 +1,334 @@
 // Uses non-blocking I/O to accept connections and a state machine to manage their progress
 // Single-threaded, so similar to async in Python or Node.js
+use crate::accept::{self, Recovery};
+use crate::backoff::SpinMode;
+use crate::config::Config;
+use crate::error::ConnectionError;
+use crate::handler::{self, SharedHandler};
+use crate::limit::{InFlight, InFlightGuard, Limit};
+use crate::server;
+use crate::shutdown;
+use crate::timeout;
+use slab::Slab;
+use std::collections::BTreeSet;
 use std::io;
 use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::thread::sleep;
-use std::time::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
 
 #[allow(clippy::large_enum_variant)]
-enum ConnectionState<'a> {
+enum ConnectionState {
     ReadingRequest { request: [u8; 1024], read: usize },
-    WritingResponse { response: &'a [u8], written: usize },
+    WritingResponse { response: Vec<u8>, written: usize },
     Flushing,
 }
 
-pub fn main() {
-    let listener = TcpListener::bind("localhost:3000").unwrap();
+// A connection, plus what we're waiting on from it.
+struct Connection {
+    stream: TcpStream,
+    state: ConnectionState,
+    // when the client has to be done with whatever it's up to
+    deadline: Instant,
+    // the settings it was accepted under
+    config: Config,
+    // where its entry is in `Connections::fds`
+    slot: usize,
+    _in_flight: InFlightGuard,
+}
+
+// The connections we're handling, keyed by id, along with the array we hand to poll(2) to find
+// out which of them can make progress. The array lives as long as the server, and adding or
+// removing a connection doesn't touch any others' entries.
+struct Connections {
+    slab: Slab<Connection>,
+    // the shutdown pipe and the listener, then one for each connection in no particular order
+    fds: Vec<libc::pollfd>,
+    // the id of the connection each entry in `fds` from `FIRST_CONNECTION` on is for
+    ids: Vec<usize>,
+    in_flight: InFlight,
+}
+
+// Where things other than connections go in `Connections::fds`. Their fd is -1, which poll(2)
+// skips, while we're not waiting on them.
+const SIGNALS_SLOT: usize = 0;
+const LISTENER_SLOT: usize = 1;
+const FIRST_CONNECTION: usize = 2;
+
+impl Connections {
+    fn new(in_flight: InFlight) -> Self {
+        let unused = libc::pollfd {
+            fd: -1,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        Connections {
+            slab: Slab::new(),
+            fds: vec![unused; FIRST_CONNECTION],
+            ids: Vec::new(),
+            in_flight,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    fn insert(
+        &mut self,
+        stream: TcpStream,
+        state: ConnectionState,
+        deadline: Instant,
+        config: Config,
+    ) -> usize {
+        let fd = stream.as_raw_fd();
+        let id = self.slab.insert(Connection {
+            stream,
+            state,
+            deadline,
+            config,
+            slot: self.fds.len(),
+            _in_flight: self.in_flight.start(),
+        });
+        self.fds.push(libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        self.ids.push(id);
+        id
+    }
+
+    fn remove(&mut self, id: usize) {
+        let Some(connection) = self.slab.try_remove(id) else {
+            return;
+        };
+        // move the last entry into the hole
+        let slot = connection.slot;
+        self.fds.swap_remove(slot);
+        self.ids.swap_remove(slot - FIRST_CONNECTION);
+        if let Some(&moved) = self.ids.get(slot - FIRST_CONNECTION) {
+            self.slab[moved].slot = slot;
+        }
+    }
+
+    // Wait for the connection to become writable rather than readable.
+    fn want_write(&mut self, id: usize) {
+        self.fds[self.slab[id].slot].events = libc::POLLOUT;
+    }
+
+    // Which connections can make progress, asking the kernel about all of them in one go rather
+    // than trying a read or write on each.
+    // If none can yet, wait up to `timeout` (forever if `None`) for one to, or for the shutdown
+    // pipe or the listener, if we're given them, to become readable.
+    fn ready(
+        &mut self,
+        ready: &mut Vec<usize>,
+        signals: Option<RawFd>,
+        listener: Option<RawFd>,
+        timeout: Option<Duration>,
+    ) {
+        ready.clear();
+        let timeout = match timeout {
+            // round up, so we don't wake just before whatever we're waiting for
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        if self.ids.is_empty() && timeout == 0 {
+            return;
+        }
+
+        self.fds[SIGNALS_SLOT].fd = signals.unwrap_or(-1);
+        self.fds[LISTENER_SLOT].fd = listener.unwrap_or(-1);
+        let n = unsafe {
+            libc::poll(
+                self.fds.as_mut_ptr(),
+                self.fds.len() as libc::nfds_t,
+                timeout,
+            )
+        };
+        if n <= 0 {
+            // nothing's ready, or a signal interrupted us and we'll ask again next time around
+            return;
+        }
+        for (fd, &id) in self.fds[FIRST_CONNECTION..].iter().zip(&self.ids) {
+            if fd.revents != 0 {
+                ready.push(id);
+            }
+        }
+    }
+}
+
+pub fn serve(listener: TcpListener, mut config: Config, handler: SharedHandler) {
+    let signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    let mut limit = Limit::new(config.max_connections);
+
     listener.set_nonblocking(true).unwrap();
-    let mut connections = Vec::new();
+    server::ready(&in_flight);
+    let mut listener = Some(listener);
+    let mut connections = Connections::new(in_flight);
+    // when each connection has to be done with what it's up to, soonest first
+    // (entries stay behind when a connection moves on, so check they're still current)
+    let mut deadlines = BTreeSet::new();
+    // set when we've run out of resources, so existing connections get a chance to finish
+    let mut paused_until = None;
+    // set once we've been asked to shut down
+    let mut drain_deadline = None;
+    let mut ready = Vec::new();
+    let mut completed = Vec::new();
     loop {
-        // try to accept a new connection
-        // this does a context switch to the kernel -- a syscall
-        match listener.accept() {
-            // we got a new connection!
-            Ok((connection, _)) => {
-                connection.set_nonblocking(true).unwrap();
-
-                let state = ConnectionState::ReadingRequest {
-                    request: [0u8; 1024],
-                    read: 0,
-                };
-                connections.push((connection, state));
+        if drain_deadline.is_none() && signals.requested() {
+            // stop accepting new connections, and give the ones we have time to finish
+            listener = None;
+            drain_deadline = Some(Instant::now() + shutdown::DRAIN_TIMEOUT);
+        }
+        if let Some(deadline) = drain_deadline {
+            if connections.len() == 0 || Instant::now() >= deadline {
+                shutdown::report(connections.len());
+                return;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-            // some other error occurred
-            Err(e) => panic!("encountered IO error: {e}"),
-        };
+        }
+
+        if paused_until.is_some_and(|until| Instant::now() >= until) {
+            paused_until = None;
+        }
+        if config.reload(&signals) {
+            limit.set_max(config.max_connections);
+        }
+
+        // accept every connection that's waiting, unless we're backing off or at the limit, so
+        // they're all seen to by the one poll below
+        // each accept does a context switch to the kernel -- a syscall
+        while let (Some(listener), None) = (&listener, paused_until) {
+            if !limit.has_room(connections.len()) {
+                break;
+            }
+            match listener.accept() {
+                // we got a new connection!
+                Ok((connection, _)) => {
+                    if let Err(e) = connection.set_nonblocking(true) {
+                        println!("failed to handle connection: {}", ConnectionError::from(e));
+                        continue;
+                    }
+
+                    let state = ConnectionState::ReadingRequest {
+                        request: [0u8; 1024],
+                        read: 0,
+                    };
+                    // the client has the idle timeout to start its request
+                    let deadline = Instant::now() + config.timeouts.idle;
+                    let id = connections.insert(connection, state, deadline, config);
+                    deadlines.insert((deadline, id));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // some other error occurred
+                Err(e) => {
+                    if accept::recover(e) == Recovery::Backoff {
+                        paused_until = Some(Instant::now() + accept::BACKOFF);
+                    }
+                    break;
+                }
+            }
+        }
 
-        let mut completed = Vec::new();
+        // only the connections that can make progress are worth a read or write
+        if config.spin == SpinMode::Adaptive {
+            // rather than spin, sleep until a connection can make progress, a new one arrives,
+            // we're asked to shut down or there's a deadline to deal with
+            // (the shutdown pipe stays readable once we're shutting down, by which point the
+            // listener is gone)
+            let signals = listener.as_ref().map(|_| signals.as_raw_fd());
+            let listener = listener
+                .as_ref()
+                .filter(|_| paused_until.is_none() && !limit.is_full())
+                .map(|listener| listener.as_raw_fd());
+            let next_deadline = deadlines.first().map(|&(deadline, _)| deadline);
+            let timeout = [paused_until, drain_deadline, next_deadline]
+                .into_iter()
+                .flatten()
+                .min()
+                .map(|at| at.saturating_duration_since(Instant::now()));
+            connections.ready(&mut ready, signals, listener, timeout);
+        } else {
+            connections.ready(&mut ready, None, None, Some(Duration::ZERO));
+        }
+        completed.clear();
 
-        'next: for (i, (connection, state)) in connections.iter_mut().enumerate() {
+        'next: for &id in &ready {
+            let Connection {
+                stream: connection,
+                state,
+                deadline,
+                config,
+                ..
+            } = &mut connections.slab[id];
             if let ConnectionState::ReadingRequest { request, read } = state {
                 // try reading from the stream
                 loop {
                     match connection.read(&mut request[*read..]) {
                         Ok(0) => {
                             println!("client disconnected unexpectedly");
-                            completed.push(i);
+                            completed.push(id);
                             continue 'next;
                         }
                         Ok(num_bytes) => {
+                            // the request has started, it gets the header timeout to finish
+                            if *read == 0 {
+                                *deadline = Instant::now() + config.timeouts.header;
+                                deadlines.insert((*deadline, id));
+                            }
                             // keep track of how many bytes we've read
                             *read += num_bytes;
                         }
                         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                             continue 'next;
                         }
-                        // some other error occurred
-                        Err(e) => panic!("encountered IO error: {e}"),
+                        // something went wrong with this connection, give up on it
+                        Err(e) => {
+                            println!("failed to handle connection: {}", ConnectionError::from(e));
+                            completed.push(id);
+                            continue 'next;
+                        }
                     }
                     // have we reached the end of the request?
-                    if request.get(*read - 4..*read) == Some(b"\r\n\r\n") {
+                    if *read >= 4 && request.get(*read - 4..*read) == Some(b"\r\n\r\n") {
                         break;
                     }
+                    // or run out of room for it?
+                    if *read == request.len() {
+                        let e = handler::request_too_large(connection);
+                        println!("failed to handle connection: {e}");
+                        completed.push(id);
+                        continue 'next;
+                    }
                 }
                 // we're done, print the request
-                let _request = String::from_utf8_lossy(&request[..*read]);
-                // println!("{request}");
-                // sleep for 10 ms to simulate doing some work
-                sleep(Duration::from_millis(10));
-                let response = concat!(
-                    "HTTP/1.1 200 OK\r\n",
-                    "Content-Length: 13\n",
-                    "Connection: close\r\n\r\n",
-                    "Hello world!\n"
-                );
+                // println!("{}", String::from_utf8_lossy(&request[..*read]));
+                // simulate doing some work, holding up every other connection meanwhile
+                config.work.run();
+                let response = handler.respond(&request[..*read]);
 
                 *state = ConnectionState::WritingResponse {
-                    response: response.as_bytes(),
+                    response,
                     written: 0,
                 };
+                *deadline = Instant::now() + config.timeouts.write;
+                deadlines.insert((*deadline, id));
+                connections.want_write(id);
             };
+            let Connection {
+                stream: connection,
+                state,
+                ..
+            } = &mut connections.slab[id];
             if let ConnectionState::WritingResponse { response, written } = state {
-                // try writing to the stream
-                loop {
+                // try writing to the stream, until we've written the entire response
+                while *written < response.len() {
                     match connection.write(&response[*written..]) {
                         Ok(0) => {
                             println!("client disconnected unexpectedly");
-                            completed.push(i);
+                            completed.push(id);
                             continue 'next;
                         }
                         Ok(num_bytes) => {
@@ -96,12 +338,12 @@ pub fn main() {
                         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                             continue 'next;
                         }
-                        // some other error occurred
-                        Err(e) => panic!("encountered IO error: {e}"),
-                    }
-                    // have we written the entire response?
-                    if *written == response.len() {
-                        break;
+                        // something went wrong with this connection, give up on it
+                        Err(e) => {
+                            println!("failed to handle connection: {}", ConnectionError::from(e));
+                            completed.push(id);
+                            continue 'next;
+                        }
                     }
                 }
                 *state = ConnectionState::Flushing;
@@ -114,13 +356,89 @@ pub fn main() {
                         continue 'next;
                     }
                     // some other error occurred
-                    Err(e) => panic!("encountered IO error: {e}"),
+                    Err(e) => {
+                        println!("failed to handle connection: {}", ConnectionError::from(e));
+                        completed.push(id);
+                        continue 'next;
+                    }
                 }
-                completed.push(i);
+                completed.push(id);
+            }
+        }
+
+        // give up on connections that have run out of time
+        let now = Instant::now();
+        while let Some(&(deadline, id)) = deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            deadlines.pop_first();
+            let Some(connection) = connections.slab.get_mut(id) else {
+                continue;
+            };
+            if connection.deadline != deadline {
+                continue;
             }
+            let e = match connection.state {
+                ConnectionState::ReadingRequest { read, .. } => {
+                    timeout::request_timed_out(&mut connection.stream, read)
+                }
+                _ => ConnectionError::from(io::Error::from(io::ErrorKind::TimedOut)),
+            };
+            println!("failed to handle connection: {e}");
+            completed.push(id);
+        }
+
+        for &id in &completed {
+            connections.remove(id);
         }
-        for i in completed.into_iter().rev() {
-            connections.remove(i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeout::Timeouts;
+    use crate::{procfs, testing};
+
+    // How much a pile of idle connections slows down the clients that are actually sending
+    // requests. Run with `cargo test --release --features benchmarks
+    // nonblocking::tests::idle_connections -- --nocapture`.
+    #[test]
+    #[cfg_attr(not(feature = "benchmarks"), ignore)]
+    fn idle_connections() {
+        const REQUESTS: usize = 200;
+
+        for idle in [0, 1000, 4000] {
+            let config = Config {
+                max_connections: 10_000,
+                timeouts: Timeouts {
+                    idle: Duration::from_secs(600),
+                    ..Timeouts::default()
+                },
+                ..Config::default()
+            };
+            let addr = testing::spawn_server(move |listener| {
+                serve(listener, config, testing::hello_world())
+            });
+            let _idle = testing::connect_clients(addr, idle);
+
+            let start = Instant::now();
+            let cpu_before = procfs::cpu_time("self").unwrap();
+            for _ in 0..REQUESTS / 20 {
+                for response in testing::simultaneous_requests(addr, 20) {
+                    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+                }
+            }
+            let elapsed = start.elapsed();
+            // the clients are in this process too, but they're mostly waiting on the server
+            let cpu = procfs::cpu_time("self").unwrap() - cpu_before;
+            println!(
+                "{idle:>5} idle connections: {REQUESTS} requests in {elapsed:?}, {:.0} requests/s, \
+                 {:.0}% CPU",
+                REQUESTS as f64 / elapsed.as_secs_f64(),
+                cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+            );
         }
     }
 }
@@ -1,42 +1,111 @@
 // Uses non-blocking I/O, but spins in the connection loop rather than doing a state machine.
+use crate::accept::{self, Recovery};
+use crate::backoff::{Backoff, SpinMode};
+use crate::config::Config;
+use crate::error::ConnectionError;
+use crate::handler::{self, Handler, SharedHandler};
+use crate::limit::{InFlight, Limit};
+use crate::server;
+use crate::shutdown;
+use crate::timeout::{self, Timeouts};
+use crate::workload::Workload;
 use std::io;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::thread::sleep;
 use std::thread::spawn;
-use std::time::Duration;
+use std::time::Instant;
+
+pub fn serve(listener: TcpListener, mut config: Config, handler: SharedHandler) {
+    let signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    let mut limit = Limit::new(config.max_connections);
+    let mut backoff = Backoff::new(config.spin);
 
-pub fn main() {
-    let listener = TcpListener::bind("localhost:3000").unwrap();
     listener.set_nonblocking(true).unwrap();
-    loop {
+    server::ready(&in_flight);
+    while !signals.requested() {
+        if config.reload(&signals) {
+            limit.set_max(config.max_connections);
+            backoff = Backoff::new(config.spin);
+        }
+        // at the limit: leave new connections in the backlog until a thread finishes
+        if !limit.has_room(in_flight.count()) {
+            backoff.idle();
+            continue;
+        }
         let connection = match listener.accept() {
             // we got a new connection!
-            Ok((connection, _)) => connection,
+            Ok((connection, _)) => {
+                backoff.reset();
+                connection
+            }
             // no new connection, try again
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                backoff.idle();
                 continue;
             }
             // some other error occurred
-            Err(e) => panic!("encountered IO error: {e}"),
+            Err(e) => {
+                if accept::recover(e) == Recovery::Backoff {
+                    sleep(accept::BACKOFF);
+                }
+                continue;
+            }
         };
-        connection.set_nonblocking(true).unwrap();
+        if let Err(e) = connection.set_nonblocking(true) {
+            println!("failed to handle connection: {}", ConnectionError::from(e));
+            continue;
+        }
 
-        spawn(|| {
-            if let Err(e) = handle_connection(connection) {
+        let guard = in_flight.start();
+        let handler = handler.clone();
+        spawn(move || {
+            if let Err(e) = handle_connection(
+                connection,
+                &config.timeouts,
+                config.work,
+                config.spin,
+                &*handler,
+            ) {
                 println!("failed to handle connection: {e}")
             }
+            drop(guard);
         });
     }
+
+    // stop accepting new connections, and give the ones we have time to finish
+    drop(listener);
+    shutdown::report(in_flight.wait(Instant::now() + shutdown::DRAIN_TIMEOUT));
 }
 
-fn handle_connection(mut connection: TcpStream) -> io::Result<()> {
+fn handle_connection(
+    mut connection: TcpStream,
+    timeouts: &Timeouts,
+    work: Workload,
+    spin: SpinMode,
+    handler: &dyn Handler,
+) -> Result<(), ConnectionError> {
+    let mut backoff = Backoff::new(spin);
     let mut read = 0;
     let mut request = [0u8; 1024];
+    // the request has to start within the idle timeout, then gets the header timeout to finish
+    let mut deadline = Instant::now() + timeouts.idle;
 
     loop {
         // try reading from the stream
-        let num_bytes = connection.read(&mut request[read..])?;
+        let num_bytes = match connection.read(&mut request[read..]) {
+            Ok(num_bytes) => num_bytes,
+            // nothing there yet, spin until there is or the client runs out of time
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(timeout::request_timed_out(&mut connection, read));
+                }
+                backoff.idle();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         // the client disconnected
         if num_bytes == 0 {
@@ -44,32 +113,44 @@ fn handle_connection(mut connection: TcpStream) -> io::Result<()> {
             return Ok(());
         }
 
+        if read == 0 {
+            deadline = Instant::now() + timeouts.header;
+        }
         // keep track of how many bytes we've read
         read += num_bytes;
 
         // have we reached the end of the request?
-        if request.get(read - 4..read) == Some(b"\r\n\r\n") {
+        if read >= 4 && request.get(read - 4..read) == Some(b"\r\n\r\n") {
             break;
         }
+        // or run out of room for it?
+        if read == request.len() {
+            return Err(handler::request_too_large(&mut connection));
+        }
     }
 
-    let _request = String::from_utf8_lossy(&request[..read]);
-    // println!("{request}");
-    sleep(Duration::from_millis(10));
-
-    // "Hello World!" in HTTP
-    let response = concat!(
-        "HTTP/1.1 200 OK\r\n",
-        "Content-Length: 13\n",
-        "Connection: close\r\n\r\n",
-        "Hello world!\n"
-    );
+    // println!("{}", String::from_utf8_lossy(&request[..read]));
+    work.run();
+    let response = handler.respond(&request[..read]);
 
     let mut written = 0;
+    let deadline = Instant::now() + timeouts.write;
 
-    loop {
+    // until we've written the whole response
+    while written < response.len() {
         // write the remaining response bytes
-        let num_bytes = connection.write(response[written..].as_bytes())?;
+        let num_bytes = match connection.write(&response[written..]) {
+            Ok(num_bytes) => num_bytes,
+            // the send buffer is full, spin until there's room or the client runs out of time
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+                }
+                backoff.idle();
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         // the client disconnected
         if num_bytes == 0 {
@@ -78,12 +159,8 @@ fn handle_connection(mut connection: TcpStream) -> io::Result<()> {
         }
 
         written += num_bytes;
-
-        // have we written the whole response yet?
-        if written == response.len() {
-            break;
-        }
     }
 
-    connection.flush()
+    connection.flush()?;
+    Ok(())
 }
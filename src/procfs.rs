@@ -0,0 +1,60 @@
+// What a process is using, read from /proc. `pid` can be "self" for this process.
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+// User plus system CPU time used so far.
+pub fn cpu_time(pid: &str) -> io::Result<Duration> {
+    let fields = stat(pid)?;
+    // utime and stime are fields 14 and 15, in clock ticks
+    let ticks: u64 = field(&fields, 14)? + field(&fields, 15)?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    Ok(Duration::from_millis(ticks * 1000 / ticks_per_second))
+}
+
+pub fn threads(pid: &str) -> io::Result<usize> {
+    // num_threads is field 20
+    Ok(field(&stat(pid)?, 20)? as usize)
+}
+
+pub fn open_fds(pid: &str) -> io::Result<usize> {
+    Ok(fs::read_dir(format!("/proc/{pid}/fd"))?.count())
+}
+
+// The fields of /proc/PID/stat after the command name, which is in parentheses and may contain
+// spaces, so the first of them is field 3.
+fn stat(pid: &str) -> io::Result<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    let (_, fields) = stat
+        .rsplit_once(')')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed stat"))?;
+    Ok(fields.split_whitespace().map(String::from).collect())
+}
+
+// Field `n` of /proc/PID/stat, counting from 1 as proc(5) does.
+fn field(fields: &[String], n: usize) -> io::Result<u64> {
+    fields
+        .get(n - 3)
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no stat field {n}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn reads_this_process() {
+        // other tests come and go meanwhile, so just check we see at least what's ours
+        let thread = spawn(|| sleep(Duration::from_millis(200)));
+        assert!(threads("self").unwrap() >= 2);
+        thread.join().unwrap();
+
+        let _file = fs::File::open("/proc/self/stat").unwrap();
+        assert!(open_fds("self").unwrap() >= 1);
+        assert!(cpu_time("self").is_ok());
+
+        assert!(threads("no-such-process").is_err());
+    }
+}
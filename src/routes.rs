@@ -0,0 +1,189 @@
+// What to answer with, path by path: fixed text for some paths, files from a directory for
+// others. Set up with `route` and `static` in the settings file or as flags. With neither, every
+// request gets hello world, as it always has.
+use crate::handler::{Handler, HELLO_WORLD};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Routes {
+    // exact paths, and the text they answer with
+    pages: Vec<(String, String)>,
+    // path prefixes, and the directories the files under them come from
+    roots: Vec<(String, PathBuf)>,
+}
+
+impl Routes {
+    // Add a `route <PATH> <TEXT>` or a `static <PREFIX> <DIRECTORY>`, as `kind` says. Errors
+    // start with the kind, so they can be pointed at the flag or the line in a file.
+    pub fn add(&mut self, kind: &str, value: Option<&str>) -> Result<(), String> {
+        let (path, rest) = value
+            .and_then(|value| value.split_once(' '))
+            .map(|(path, rest)| (path, rest.trim()))
+            .filter(|(path, rest)| path.starts_with('/') && !rest.is_empty())
+            .ok_or(match kind {
+                "route" => "route needs a path and what to answer, like `/health ok`",
+                _ => "static needs a path and a directory, like `/assets ./public`",
+            })?;
+        match kind {
+            "route" => self.pages.push((path.to_string(), rest.to_string())),
+            _ => {
+                if !Path::new(rest).is_dir() {
+                    return Err(format!("static: {rest} isn't a directory"));
+                }
+                let prefix = path.trim_end_matches('/');
+                self.roots.push((prefix.to_string(), PathBuf::from(rest)));
+            }
+        }
+        Ok(())
+    }
+
+    // Forget the `route`s or `static`s, as `kind` says, for ones given later to replace them.
+    pub fn clear(&mut self, kind: &str) {
+        match kind {
+            "route" => self.pages.clear(),
+            _ => self.roots.clear(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty() && self.roots.is_empty()
+    }
+
+    // The response to a GET for `path`.
+    fn respond(&self, path: &str) -> Vec<u8> {
+        if let Some((_, text)) = self.pages.iter().find(|(page, _)| page == path) {
+            return response("200 OK", format!("{text}\n").as_bytes());
+        }
+        for (prefix, root) in &self.roots {
+            let Some(file) = path
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+            else {
+                continue;
+            };
+            // nothing outside the directory: no `..`, and nothing absolute
+            let file = Path::new(file);
+            if !file.components().all(|c| matches!(c, Component::Normal(_))) {
+                return response("404 Not Found", b"");
+            }
+            if let Ok(contents) = fs::read(root.join(file)) {
+                return response("200 OK", &contents);
+            }
+        }
+        response("404 Not Found", b"")
+    }
+}
+
+fn response(status: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Answers requests from its `Routes`, which can be replaced while it's serving. Requests already
+// underway finish with the routes they started with.
+#[derive(Default)]
+pub struct Router(RwLock<Arc<Routes>>);
+
+impl Router {
+    pub fn new(routes: Routes) -> Router {
+        Router(RwLock::new(Arc::new(routes)))
+    }
+
+    pub fn set(&self, routes: Routes) {
+        *self.0.write().unwrap() = Arc::new(routes);
+    }
+}
+
+impl Handler for Router {
+    fn respond(&self, request: &[u8]) -> Vec<u8> {
+        let routes = self.0.read().unwrap().clone();
+        if routes.is_empty() {
+            return HELLO_WORLD.to_vec();
+        }
+
+        // the path from the request line, without any query
+        let line = request.split(|&b| b == b'\r').next().unwrap();
+        let line = String::from_utf8_lossy(line);
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            ["GET", target, _] => routes.respond(target.split('?').next().unwrap()),
+            _ => response("400 Bad Request", b""),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn get(router: &Router, path: &str) -> String {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        String::from_utf8(router.respond(request.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn answers_from_its_routes() {
+        let root = testing::temp_file("");
+        std::fs::remove_file(&root).unwrap();
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("css/site.css"), "body {}").unwrap();
+
+        let mut routes = Routes::default();
+        routes.add("route", Some("/health ok")).unwrap();
+        let dir = format!("/assets {}", root.display());
+        routes.add("static", Some(&dir)).unwrap();
+        let router = Router::new(routes);
+
+        assert!(get(&router, "/health").ends_with("\r\n\r\nok\n"));
+        assert!(get(&router, "/health?verbose").ends_with("\r\n\r\nok\n"));
+        assert!(get(&router, "/assets/css/site.css").ends_with("\r\n\r\nbody {}"));
+        for missing in [
+            "/",
+            "/assets/none.css",
+            "/assets/../assets/css/site.css",
+            "/assets",
+        ] {
+            let response = get(&router, missing);
+            assert!(
+                response.starts_with("HTTP/1.1 404"),
+                "{missing}: {response}"
+            );
+        }
+        let response = String::from_utf8(router.respond(b"BREW /pot HTTP/1.1\r\n\r\n")).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+
+        // and with none at all, it's hello world whatever the path
+        router.set(Routes::default());
+        assert_eq!(get(&router, "/health").as_bytes(), HELLO_WORLD);
+    }
+
+    #[test]
+    fn checks_what_its_given() {
+        let mut routes = Routes::default();
+        for (kind, value, error) in [
+            ("route", "/health", "route needs a path and what to answer"),
+            (
+                "route",
+                "health ok",
+                "route needs a path and what to answer",
+            ),
+            ("static", "/assets", "static needs a path and a directory"),
+            (
+                "static",
+                "/assets /nonexistent",
+                "static: /nonexistent isn't a directory",
+            ),
+        ] {
+            let e = routes.add(kind, Some(value)).unwrap_err();
+            assert!(e.starts_with(error), "{e}");
+        }
+        assert!(routes.is_empty());
+    }
+}
@@ -0,0 +1,764 @@
+// A tiny single-threaded async runtime: a task scheduler plus a mio-based reactor.
+// Each `Runtime` owns its own scheduler and reactor, so several can coexist in one process.
+use mio::event::Source;
+use mio::{Events, Poll, Token};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    os::fd::AsRawFd,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub struct Waker(Arc<dyn Fn() + Send + Sync>);
+
+impl Waker {
+    pub fn wake(&self) {
+        (self.0)()
+    }
+}
+
+pub trait Future {
+    type Output;
+
+    fn poll(&mut self, waker: Waker) -> Option<Self::Output>;
+}
+
+// Reserved for the reactor's own mio::Waker; tokens for sources are raw fds, so never collide.
+const WAKER: Token = Token(usize::MAX);
+
+// Identifies a timer, so it can be removed before it fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer((Instant, usize));
+
+pub struct Reactor {
+    poll: RefCell<Poll>,
+    tasks: RefCell<HashMap<Token, Waker>>,
+    // wakers to call once a deadline passes, the usize keeps equal deadlines apart
+    timers: RefCell<BTreeMap<(Instant, usize), Waker>>,
+    next_timer: Cell<usize>,
+}
+
+impl Reactor {
+    fn new() -> io::Result<Reactor> {
+        Ok(Reactor {
+            poll: RefCell::new(Poll::new()?),
+            tasks: RefCell::new(HashMap::new()),
+            timers: RefCell::new(BTreeMap::new()),
+            next_timer: Cell::new(0),
+        })
+    }
+
+    pub fn add<S: Source + AsRawFd>(&self, source: &mut S, waker: Waker) -> io::Result<()> {
+        let token = Token(source.as_raw_fd() as usize); // Assigning a token using raw fd
+        self.poll.borrow().registry().register(
+            source,
+            token,
+            mio::Interest::READABLE | mio::Interest::WRITABLE,
+        )?;
+        self.tasks.borrow_mut().insert(token, waker);
+        Ok(())
+    }
+
+    pub fn remove<S: Source + AsRawFd>(&self, source: &mut S) {
+        let token = Token(source.as_raw_fd() as usize);
+        if let Err(e) = self.poll.borrow().registry().deregister(source) {
+            eprintln!(
+                "Failed to deregister source with token {:?} due to error {:?}",
+                token, e
+            ); // or handle it appropriately
+        }
+        self.tasks.borrow_mut().remove(&token);
+    }
+
+    // Wake `waker` once `deadline` has passed.
+    pub fn add_timer(&self, deadline: Instant, waker: Waker) -> Timer {
+        let id = self.next_timer.get();
+        self.next_timer.set(id + 1);
+        self.timers.borrow_mut().insert((deadline, id), waker);
+        Timer((deadline, id))
+    }
+
+    // Forget a timer that's no longer needed, if it hasn't fired yet.
+    pub fn remove_timer(&self, timer: Timer) {
+        self.timers.borrow_mut().remove(&timer.0);
+    }
+
+    // Forget every registered waker, dropping any tasks only they were keeping alive.
+    fn clear(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.borrow_mut());
+        let timers = std::mem::take(&mut *self.timers.borrow_mut());
+        drop(tasks);
+        drop(timers);
+    }
+
+    // Drive tasks forward, blocking until an event arrives, a timer fires or the timeout elapses.
+    fn wait(&self, timeout: Option<Duration>) {
+        let mut events = Events::with_capacity(1024);
+
+        // don't sleep past the next timer
+        let next_timer = self
+            .timers
+            .borrow()
+            .keys()
+            .next()
+            .map(|(deadline, _)| *deadline);
+        let timeout = match (timeout, next_timer) {
+            (timeout, None) => timeout,
+            (None, Some(deadline)) => Some(deadline.saturating_duration_since(Instant::now())),
+            (Some(timeout), Some(deadline)) => {
+                Some(timeout.min(deadline.saturating_duration_since(Instant::now())))
+            }
+        };
+
+        match self.poll.borrow_mut().poll(&mut events, timeout) {
+            Ok(()) => {}
+            // a signal handler ran, anything it wants to tell us comes through a registered source
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => panic!("encountered IO error: {e}"),
+        }
+
+        self.fire_timers();
+
+        for event in events.iter() {
+            let token = event.token();
+
+            // another thread just wanted to interrupt the poll, there's no task to wake
+            if token == WAKER {
+                continue;
+            }
+
+            // wake the task
+            let waker = self.tasks.borrow().get(&token).cloned();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    fn fire_timers(&self) {
+        let now = Instant::now();
+        loop {
+            let mut timers = self.timers.borrow_mut();
+            let Some(entry) = timers.first_entry() else {
+                break;
+            };
+            if entry.key().0 > now {
+                break;
+            }
+            let waker = entry.remove();
+            drop(timers);
+            waker.wake();
+        }
+    }
+}
+
+// A spawned future, plus the bookkeeping needed to schedule it.
+struct Task {
+    id: usize,
+    // `None` once the future has completed
+    future: Mutex<Option<Box<dyn Future<Output = ()> + Send>>>,
+    // whether the task is already sitting in the run queue
+    scheduled: AtomicBool,
+    // created once at spawn time and handed out on every poll
+    waker: Waker,
+}
+
+// The scheduler.
+struct Scheduler {
+    // every task that hasn't completed yet, the run queue and wakers only refer to these
+    tasks: Mutex<HashMap<usize, Arc<Task>>>,
+    next_id: AtomicUsize,
+    runnable: Mutex<VecDeque<Arc<Task>>>,
+    // set while the runtime thread is (about to be) blocked in the reactor
+    parked: AtomicBool,
+    // interrupts the reactor's poll, so wakes from other threads are noticed right away
+    waker: mio::Waker,
+}
+
+impl Scheduler {
+    fn new(reactor: &Reactor) -> io::Result<Scheduler> {
+        Ok(Scheduler {
+            tasks: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+            runnable: Mutex::new(VecDeque::new()),
+            parked: AtomicBool::new(false),
+            waker: mio::Waker::new(reactor.poll.borrow().registry(), WAKER)?,
+        })
+    }
+
+    fn spawn(self: &Arc<Self>, future: impl Future<Output = ()> + Send + 'static) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let scheduler = Arc::downgrade(self);
+
+        let task = Arc::new_cyclic(|task: &Weak<Task>| {
+            let task = task.clone();
+            // a waker that pushes the task back on, unless it has finished or been dropped
+            let wake = move || {
+                if let (Some(scheduler), Some(task)) = (scheduler.upgrade(), task.upgrade()) {
+                    scheduler.schedule(task);
+                }
+            };
+
+            Task {
+                id,
+                future: Mutex::new(Some(Box::new(future))),
+                scheduled: AtomicBool::new(false),
+                waker: Waker(Arc::new(wake)),
+            }
+        });
+
+        self.tasks.lock().unwrap().insert(id, task.clone());
+        self.schedule(task);
+    }
+
+    fn schedule(&self, task: Arc<Task>) {
+        // a task that's already queued will see this wake when it's polled
+        if task.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.runnable.lock().unwrap().push_back(task);
+        self.unpark();
+    }
+
+    // Kick the runtime thread out of the reactor if it's blocked there.
+    fn unpark(&self) {
+        if self.parked.load(Ordering::SeqCst) {
+            if let Err(e) = self.waker.wake() {
+                eprintln!("failed to wake the reactor: {e}");
+            }
+        }
+    }
+
+    // Collect I/O events, blocking in the reactor if there's nothing else to do.
+    fn park(&self, reactor: &Reactor, root_woken: &AtomicBool) {
+        self.parked.store(true, Ordering::SeqCst);
+        // check again now that wakers can see we're parked, otherwise we could miss a wake
+        let idle = self.runnable.lock().unwrap().is_empty() && !root_woken.load(Ordering::SeqCst);
+        // tasks that yielded are still queued, so only peek at the reactor for them
+        reactor.wait(if idle { None } else { Some(Duration::ZERO) });
+        self.parked.store(false, Ordering::SeqCst);
+    }
+
+    // Poll the tasks that are runnable right now.
+    //
+    // Tasks woken during the tick wait for the next one, so a task that keeps yielding
+    // can't stop the reactor from being checked.
+    fn tick(&self) {
+        let queued = self.runnable.lock().unwrap().len();
+        for _ in 0..queued {
+            // pop a runnable task off the queue
+            let Some(task) = self.runnable.lock().unwrap().pop_front() else {
+                break;
+            };
+
+            // wakes from here on need to queue the task again
+            task.scheduled.store(false, Ordering::SeqCst);
+
+            // poll the task
+            let mut future = task.future.lock().unwrap();
+            let Some(pending) = future.as_mut() else {
+                continue;
+            };
+            reset_budget();
+            if pending.poll(task.waker.clone()).is_some() {
+                *future = None;
+                self.tasks.lock().unwrap().remove(&task.id);
+            }
+        }
+    }
+
+    // Drop every task that hasn't completed yet.
+    fn clear(&self) {
+        let runnable = std::mem::take(&mut *self.runnable.lock().unwrap());
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        drop(runnable);
+        drop(tasks);
+    }
+}
+
+// How many operations a task may perform in a single poll before it has to yield.
+const BUDGET: usize = 128;
+
+thread_local! {
+    // what's left of the current poll's budget
+    static REMAINING: Cell<usize> = const { Cell::new(BUDGET) };
+}
+
+fn reset_budget() {
+    REMAINING.with(|remaining| remaining.set(BUDGET));
+}
+
+// Account for one I/O operation by the task being polled.
+//
+// Returns false once the task has used up its budget for this poll. In that case the task
+// has already been woken, and should return `None` so that other tasks get a turn.
+pub fn consume_budget(waker: &Waker) -> bool {
+    REMAINING.with(|remaining| match remaining.get() {
+        0 => {
+            waker.wake();
+            false
+        }
+        n => {
+            remaining.set(n - 1);
+            true
+        }
+    })
+}
+
+// A future that returns `None` once, letting every other runnable task go first.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(&mut self, waker: Waker) -> Option<()> {
+        if self.yielded {
+            return Some(());
+        }
+        self.yielded = true;
+        waker.wake();
+        None
+    }
+}
+
+thread_local! {
+    // the runtime whose `block_on` is currently executing on this thread, if any
+    static CONTEXT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+// A cheap, clonable reference to a runtime, used by tasks to spawn work and register I/O.
+#[derive(Clone)]
+pub struct Handle {
+    scheduler: Arc<Scheduler>,
+    reactor: Rc<Reactor>,
+}
+
+impl Handle {
+    // The handle of the runtime driving the current task.
+    //
+    // Panics if called outside of `Runtime::block_on`.
+    pub fn current() -> Handle {
+        CONTEXT.with(|context| {
+            context
+                .borrow()
+                .clone()
+                .expect("Handle::current() called outside of a runtime")
+        })
+    }
+
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.scheduler.spawn(task);
+    }
+
+    pub fn reactor(&self) -> &Reactor {
+        &self.reactor
+    }
+
+    // Make this the current runtime until the returned guard is dropped.
+    fn enter(&self) -> EnterGuard {
+        let previous = CONTEXT.with(|context| context.replace(Some(self.clone())));
+        EnterGuard { previous }
+    }
+}
+
+struct EnterGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+pub struct Runtime {
+    handle: Handle,
+}
+
+impl Runtime {
+    pub fn new() -> io::Result<Runtime> {
+        let reactor = Reactor::new()?;
+        Ok(Runtime {
+            handle: Handle {
+                scheduler: Arc::new(Scheduler::new(&reactor)?),
+                reactor: Rc::new(reactor),
+            },
+        })
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.handle.spawn(task);
+    }
+
+    // Drive `future` to completion on the current thread, running spawned tasks alongside it.
+    //
+    // Once `future` resolves, any tasks that are still pending are dropped.
+    pub fn block_on<F: Future>(&self, mut future: F) -> F::Output {
+        let _guard = self.handle.enter();
+
+        // the root future isn't a task, it just gets polled whenever its waker fires
+        let woken = Arc::new(AtomicBool::new(true));
+        let waker = {
+            let woken = woken.clone();
+            let scheduler = self.handle.scheduler.clone();
+            Waker(Arc::new(move || {
+                woken.store(true, Ordering::SeqCst);
+                scheduler.unpark();
+            }))
+        };
+
+        let output = loop {
+            if woken.swap(false, Ordering::SeqCst) {
+                reset_budget();
+                if let Some(output) = future.poll(waker.clone()) {
+                    break output;
+                }
+            }
+
+            self.handle.scheduler.tick();
+
+            // if nothing is runnable, block on epoll until something becomes ready
+            self.handle.scheduler.park(&self.handle.reactor, &woken);
+        };
+
+        // tasks might refer to the runtime when dropped, so do this while it's still current
+        self.handle.scheduler.clear();
+        self.handle.reactor.clear();
+
+        output
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        let _guard = self.handle.enter();
+        self.handle.scheduler.clear();
+        self.handle.reactor.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // adapts a closure into a future, since we don't have `async` blocks
+    struct PollFn<F>(F);
+
+    impl<T, F: FnMut(Waker) -> Option<T>> Future for PollFn<F> {
+        type Output = T;
+
+        fn poll(&mut self, waker: Waker) -> Option<T> {
+            (self.0)(waker)
+        }
+    }
+
+    // sets its flag when dropped
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn block_on_returns_output() {
+        let runtime = Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(PollFn(|_| Some(42))), 42);
+    }
+
+    #[test]
+    fn block_on_runs_spawned_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let result = Arc::new(Mutex::new(None));
+
+        let mut spawned = false;
+        let output = runtime.block_on(PollFn(|waker: Waker| {
+            if !spawned {
+                spawned = true;
+                let result = result.clone();
+                Handle::current().spawn(PollFn(move |_| {
+                    *result.lock().unwrap() = Some("done");
+                    waker.wake();
+                    Some(())
+                }));
+            }
+            *result.lock().unwrap()
+        }));
+
+        assert_eq!(output, "done");
+    }
+
+    #[test]
+    fn repeated_wakes_poll_once() {
+        let runtime = Runtime::new().unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        let counter = polls.clone();
+        runtime.spawn(PollFn(move |waker: Waker| {
+            if counter.fetch_add(1, Ordering::SeqCst) > 0 {
+                return Some(());
+            }
+            waker.wake();
+            waker.wake();
+            waker.wake();
+            None
+        }));
+
+        // spin the root until the task has completed
+        runtime.block_on(PollFn(|waker: Waker| {
+            if polls.load(Ordering::SeqCst) >= 2 {
+                return Some(());
+            }
+            waker.wake();
+            None
+        }));
+
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn wake_after_completion_is_ignored() {
+        let runtime = Runtime::new().unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let leftover = Arc::new(Mutex::new(None));
+
+        let (counter, slot) = (polls.clone(), leftover.clone());
+        runtime.spawn(PollFn(move |waker: Waker| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            *slot.lock().unwrap() = Some(waker);
+            Some(())
+        }));
+
+        runtime.block_on(PollFn(|waker: Waker| {
+            // wake the finished task, then give the scheduler another turn
+            match leftover.lock().unwrap().take() {
+                Some(stale) => stale.wake(),
+                None if polls.load(Ordering::SeqCst) > 0 => return Some(()),
+                None => {}
+            }
+            waker.wake();
+            None
+        }));
+
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn busy_task_does_not_starve_others() {
+        let runtime = Runtime::new().unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        // a task that always finds more work to do, like a connection that's always readable
+        runtime.spawn(PollFn(|waker: Waker| loop {
+            if !consume_budget(&waker) {
+                return None;
+            }
+        }));
+
+        let flag = done.clone();
+        runtime.spawn(PollFn(move |_| {
+            flag.store(true, Ordering::SeqCst);
+            Some(())
+        }));
+
+        runtime.block_on(PollFn(|waker: Waker| {
+            if done.load(Ordering::SeqCst) {
+                return Some(());
+            }
+            waker.wake();
+            None
+        }));
+    }
+
+    #[test]
+    fn yield_now_lets_other_tasks_run() {
+        let runtime = Runtime::new().unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let log = order.clone();
+        let mut first = None;
+        runtime.spawn(PollFn(move |waker: Waker| {
+            let yielding = first.get_or_insert_with(|| {
+                log.lock().unwrap().push("first: before yield");
+                yield_now()
+            });
+            yielding.poll(waker)?;
+            log.lock().unwrap().push("first: after yield");
+            Some(())
+        }));
+
+        let log = order.clone();
+        runtime.spawn(PollFn(move |_| {
+            log.lock().unwrap().push("second");
+            Some(())
+        }));
+
+        runtime.block_on(PollFn(|waker: Waker| {
+            if order.lock().unwrap().len() == 3 {
+                return Some(());
+            }
+            waker.wake();
+            None
+        }));
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["first: before yield", "second", "first: after yield"]
+        );
+    }
+
+    #[test]
+    fn timers_wake_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let delay = std::time::Duration::from_millis(30);
+
+        let mut armed = false;
+        runtime.block_on(PollFn(|waker: Waker| {
+            if start.elapsed() >= delay {
+                return Some(());
+            }
+            if !armed {
+                armed = true;
+                Handle::current().reactor().add_timer(start + delay, waker);
+            }
+            None
+        }));
+
+        assert!(start.elapsed() >= delay);
+    }
+
+    #[test]
+    fn removed_timers_do_not_fire() {
+        let runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let delay = std::time::Duration::from_millis(30);
+
+        let mut polls = 0;
+        runtime.block_on(PollFn(|waker: Waker| {
+            polls += 1;
+            if start.elapsed() >= delay {
+                return Some(());
+            }
+            if polls == 1 {
+                let handle = Handle::current();
+                let reactor = handle.reactor();
+                let early = reactor.add_timer(start, waker.clone());
+                reactor.remove_timer(early);
+                reactor.add_timer(start + delay, waker);
+            }
+            None
+        }));
+
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn wake_from_another_thread_interrupts_the_reactor() {
+        let runtime = Runtime::new().unwrap();
+        let result = Arc::new(Mutex::new(None));
+
+        let mut started = false;
+        let output = runtime.block_on(PollFn(|waker: Waker| {
+            if !started {
+                started = true;
+                let result = result.clone();
+                // nothing is registered with the reactor, so only the mio::Waker can unblock it
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    *result.lock().unwrap() = Some("from another thread");
+                    waker.wake();
+                });
+            }
+            *result.lock().unwrap()
+        }));
+
+        assert_eq!(output, "from another thread");
+    }
+
+    #[test]
+    fn block_on_drops_pending_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        // a task that is never woken again after its first poll
+        let flag = DropFlag(dropped.clone());
+        runtime.spawn(PollFn(move |_| {
+            let _ = &flag;
+            None
+        }));
+
+        runtime.block_on(PollFn(|_| Some(())));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn runtimes_are_isolated() {
+        let first = Runtime::new().unwrap();
+        let second = Runtime::new().unwrap();
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        second.spawn(PollFn(move |_| {
+            flag.store(true, Ordering::SeqCst);
+            Some(())
+        }));
+
+        // running the first runtime must not touch the second one's tasks
+        first.block_on(PollFn(|_| Some(())));
+        assert!(!ran.load(Ordering::SeqCst));
+
+        // keep polling the root until the spawned task has had a chance to run
+        second.block_on(PollFn(|waker: Waker| {
+            if ran.load(Ordering::SeqCst) {
+                return Some(());
+            }
+            waker.wake();
+            None
+        }));
+    }
+
+    #[test]
+    fn handle_current_follows_nested_block_on() {
+        let outer = Runtime::new().unwrap();
+        outer.block_on(PollFn(|_| {
+            let before = Handle::current();
+
+            let inner = Runtime::new().unwrap();
+            inner.block_on(PollFn(|_| {
+                assert!(Arc::ptr_eq(
+                    &Handle::current().scheduler,
+                    &inner.handle.scheduler
+                ));
+                Some(())
+            }));
+
+            // leaving the inner runtime restores the outer one
+            assert!(Arc::ptr_eq(&Handle::current().scheduler, &before.scheduler));
+            Some(())
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a runtime")]
+    fn handle_current_outside_runtime_panics() {
+        Handle::current();
+    }
+}
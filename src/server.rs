@@ -0,0 +1,196 @@
+// Starting a server from code rather than the command line, on a thread of its own, and finding
+// out where it's listening. With port 0 each one gets a free port from the OS, so any number of
+// them can run side by side.
+use crate::config::Config;
+use crate::limit::InFlight;
+use crate::shutdown::{self, Signals};
+use std::cell::Cell;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+// A server that's taking connections.
+pub struct Server {
+    addr: SocketAddr,
+    thread: JoinHandle<()>,
+    in_flight: InFlight,
+    signals: Signals,
+}
+
+thread_local! {
+    // Set on a server's thread until it's ready, for `ready` to tell `Server::start` about it.
+    static STARTING: Cell<Option<Sender<InFlight>>> = const { Cell::new(None) };
+}
+
+// Listen on `config.addr`. Connections queue up in the listener's backlog from here on, before
+// anything accepts them.
+pub fn bind(config: &Config) -> io::Result<(TcpListener, SocketAddr)> {
+    let listener = TcpListener::bind(config.addr)?;
+    let addr = listener.local_addr()?;
+    Ok((listener, addr))
+}
+
+impl Server {
+    // Bind `config.addr` and run `serve` on the listener on a new thread, returning once `serve`
+    // has called `ready`. If it panics or returns before then, the server failed to start and
+    // that's the error.
+    pub fn start(
+        serve: impl FnOnce(TcpListener, Config) + Send + 'static,
+        config: Config,
+    ) -> io::Result<Server> {
+        let (listener, addr) = bind(&config)?;
+        let (starting, started) = mpsc::channel();
+        let signals = Signals::new()?;
+        let theirs = signals.clone();
+        let thread = thread::Builder::new()
+            .name(format!("server {addr}"))
+            .spawn(move || {
+                STARTING.set(Some(starting));
+                shutdown::set_current(theirs);
+                serve(listener, config)
+            })?;
+        let Ok(in_flight) = started.recv() else {
+            let reason = match thread.join() {
+                Err(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "it panicked".to_string()),
+                Ok(()) => "it stopped before it was ready".to_string(),
+            };
+            return Err(io::Error::other(format!(
+                "the server failed to start: {reason}"
+            )));
+        };
+        Ok(Server {
+            addr,
+            thread,
+            in_flight,
+            signals,
+        })
+    }
+
+    // Where the server is listening, with the port it was given if it asked for port 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // The connections the server is handling right now, counted from when they're accepted until
+    // they're closed. `count` it, or `wait` for them to finish.
+    pub fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+
+    // Have the server stop accepting and return once the connections it has are finished, or
+    // after `DRAIN_TIMEOUT` if they take longer. Returns straight away: `join` waits for it.
+    pub fn shutdown(&self) {
+        self.signals.shutdown();
+    }
+
+    // Have the server use `config` for the connections it accepts from now on. It keeps the
+    // address it's listening on, and the threadpool keeps its size.
+    pub fn reload(&self, config: Config) {
+        self.signals.reload(config);
+    }
+
+    // Whether the server has stopped, so `join` wouldn't wait.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    // Wait for the server to stop, which it does once `shutdown` is called. An error means it
+    // panicked.
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+// Called by a server once it's set up and about to take connections, which is when
+// `Server::start` returns, with the count it keeps of the connections it's handling. Does nothing
+// when the server wasn't started that way.
+pub fn ready(in_flight: &InFlight) {
+    if let Some(starting) = STARTING.take() {
+        let _ = starting.send(in_flight.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::STRATEGIES;
+    use crate::{multithread, testing, threadpool};
+    use std::net::TcpStream;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reports_where_its_listening() {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+        let serve = |listener, config| multithread::serve(listener, config, testing::hello_world());
+        let servers: Vec<_> = (0..4)
+            .map(|_| Server::start(serve, config).unwrap())
+            .collect();
+        for server in &servers {
+            assert_ne!(server.addr().port(), 0);
+            assert!(servers
+                .iter()
+                .all(|other| std::ptr::eq(other, server) || other.addr() != server.addr()));
+            let response = testing::simultaneous_requests(server.addr(), 1).remove(0);
+            assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        }
+
+        // a port that's taken is an error for whoever asked, not a panic on the server's thread
+        let taken = Config {
+            addr: servers[0].addr(),
+            ..config
+        };
+        assert!(Server::start(serve, taken).is_err());
+
+        // and so is a server that can't set itself up
+        let no_workers = Config {
+            pool: threadpool::PoolConfig {
+                workers: 0,
+                ..config.pool
+            },
+            ..config
+        };
+        let serve = |listener, config| threadpool::serve(listener, config, testing::hello_world());
+        let e = Server::start(serve, no_workers).err().unwrap();
+        assert!(e.to_string().contains("at least one worker"), "{e}");
+    }
+
+    #[test]
+    fn counts_the_connections_in_flight() {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+        for &(name, make) in STRATEGIES {
+            let strategy = make();
+            let handler = testing::hello_world();
+            let server = Server::start(
+                move |listener, config| strategy.serve(listener, config, handler),
+                config,
+            )
+            .unwrap();
+            assert_eq!(server.in_flight().count(), 0, "{name}");
+
+            // a client that hasn't sent its request yet counts from when it's accepted
+            let client = TcpStream::connect(server.addr()).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while server.in_flight().count() == 0 && Instant::now() < deadline {
+                sleep(Duration::from_millis(10));
+            }
+            assert_eq!(server.in_flight().count(), 1, "{name}");
+
+            // and stops counting once it's gone
+            drop(client);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            assert_eq!(server.in_flight().wait(deadline), 0, "{name}");
+        }
+    }
+}
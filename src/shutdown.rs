@@ -0,0 +1,192 @@
+// Stopping a server cleanly, and having it take up new settings.
+// Each server started with `Server::start` gets its own `Signals`, which `Server::shutdown` and
+// `Server::reload` pass requests through. A shutdown request writes to a pipe (the "self-pipe
+// trick"), so the server can wait for it alongside its sockets, with poll(2) or by registering
+// it with mio. New settings are taken up before the next connection, so those don't wake it.
+// Nothing here touches the process's signal handling: what SIGTERM or SIGHUP mean is up to the
+// program, see main.rs.
+use crate::config::Config;
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::cell::RefCell;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How long in-flight connections get to finish once we've stopped accepting.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One server's requests. Clones share them.
+#[derive(Clone)]
+pub struct Signals(Arc<Requests>);
+
+struct Requests {
+    shutdown: AtomicBool,
+    // settings the server hasn't taken up yet
+    reload: Mutex<Option<Config>>,
+    // the read end becomes readable, and stays that way, once shutdown is requested
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+thread_local! {
+    // Set by `Server::start` on the server's thread.
+    static CURRENT: RefCell<Option<Signals>> = const { RefCell::new(None) };
+}
+
+// The signals for the server running on this thread. A server that wasn't started by
+// `Server::start` gets some of its own, which nothing sends it.
+pub fn current() -> io::Result<Signals> {
+    match CURRENT.with_borrow(Option::clone) {
+        Some(signals) => Ok(signals),
+        None => Signals::new(),
+    }
+}
+
+// Make `signals` the ones `current` returns on this thread.
+pub fn set_current(signals: Signals) {
+    CURRENT.set(Some(signals));
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Signals> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Signals(Arc::new(Requests {
+            shutdown: AtomicBool::new(false),
+            reload: Mutex::new(None),
+            read,
+            write,
+        })))
+    }
+
+    // Ask the server to stop accepting, finish what it's in the middle of, and return.
+    pub fn shutdown(&self) {
+        if !self.0.shutdown.swap(true, Ordering::SeqCst) {
+            // the pipe only ever holds this one byte, so it can't be full
+            unsafe {
+                libc::write(
+                    self.0.write.as_raw_fd(),
+                    b"!".as_ptr() as *const libc::c_void,
+                    1,
+                )
+            };
+        }
+    }
+
+    // Ask the server to use `config` for the connections it accepts from now on, in place of
+    // any settings it hasn't got round to yet.
+    pub fn reload(&self, config: Config) {
+        *self.0.reload.lock().unwrap() = Some(config);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.0.shutdown.load(Ordering::SeqCst)
+    }
+
+    // The settings the server's been asked to use since it last took any up.
+    pub fn reload_requested(&self) -> Option<Config> {
+        self.0.reload.lock().unwrap().take()
+    }
+
+    // Block until `listener` has a connection waiting (true) or shutdown is requested (false).
+    pub fn wait_for_connection(&self, listener: &impl AsRawFd) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            if self.requested() {
+                return Ok(false);
+            }
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[0].revents != 0 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.read.as_raw_fd()
+    }
+}
+
+// So the pipe can be registered with mio like any socket.
+impl Source for Signals {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+// Tell the user how the drain went.
+pub fn report(remaining: usize) {
+    if remaining == 0 {
+        println!("all connections finished, shutting down");
+    } else {
+        println!("gave up on {remaining} connections after {DRAIN_TIMEOUT:?}, shutting down");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn servers_only_hear_their_own_requests() {
+        let first = Signals::new().unwrap();
+        let second = Signals::new().unwrap();
+        assert!(first.reload_requested().is_none());
+
+        first.reload(Config::default());
+        assert!(first.reload_requested().is_some());
+        assert!(first.reload_requested().is_none());
+        assert!(second.reload_requested().is_none());
+
+        // a shutdown wakes a server waiting for connections, and no other
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        first.clone().shutdown();
+        assert!(!first.wait_for_connection(&listener).unwrap());
+        assert!(!second.requested());
+    }
+}
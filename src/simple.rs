@@ -1,75 +1,37 @@
 // "A more elegant server from a more civilized age"
-use std::io;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::handler::{handle_blocking, SharedHandler};
+use crate::limit::InFlight;
+use crate::server;
+use crate::shutdown;
+use std::net::TcpListener;
 use std::thread::sleep;
-use std::time::Duration;
 
-pub fn main() {
-    let listener = TcpListener::bind("localhost:3000").unwrap();
-    loop {
-        let (connection, _) = listener.accept().unwrap();
-
-        if let Err(e) = handle_connection(connection) {
+pub fn serve(listener: TcpListener, mut config: Config, handler: SharedHandler) {
+    let signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    server::ready(&in_flight);
+
+    // we only ever have one connection, and it's finished by the time we get back here
+    while signals.wait_for_connection(&listener).unwrap() {
+        let connection = match listener.accept() {
+            Ok((connection, _)) => connection,
+            Err(e) => {
+                if accept::recover(e) == Recovery::Backoff {
+                    sleep(accept::BACKOFF);
+                }
+                continue;
+            }
+        };
+
+        config.reload(&signals);
+        let guard = in_flight.start();
+        if let Err(e) = handle_blocking(connection, &config.timeouts, config.work, &*handler) {
             println!("failed to handle connection: {e}")
         }
-    }
-}
-
-fn handle_connection(mut connection: TcpStream) -> io::Result<()> {
-    let mut read = 0;
-    let mut request = [0u8; 1024];
-
-    loop {
-        // try reading from the stream
-        let num_bytes = connection.read(&mut request[read..])?;
-
-        // the client disconnected
-        if num_bytes == 0 {
-            println!("client disconnected unexpectedly");
-            return Ok(());
-        }
-
-        // keep track of how many bytes we've read
-        read += num_bytes;
-
-        // have we reached the end of the request?
-        if request.get(read - 4..read) == Some(b"\r\n\r\n") {
-            break;
-        }
-    }
-
-    let _request = String::from_utf8_lossy(&request[..read]);
-    // println!("{request}");
-    sleep(Duration::from_millis(10));
-
-    // "Hello World!" in HTTP
-    let response = concat!(
-        "HTTP/1.1 200 OK\r\n",
-        "Content-Length: 13\n",
-        "Connection: close\r\n\r\n",
-        "Hello world!\n"
-    );
-
-    let mut written = 0;
-
-    loop {
-        // write the remaining response bytes
-        let num_bytes = connection.write(response[written..].as_bytes())?;
-
-        // the client disconnected
-        if num_bytes == 0 {
-            println!("client disconnected unexpectedly");
-            return Ok(());
-        }
-
-        written += num_bytes;
-
-        // have we written the whole response yet?
-        if written == response.len() {
-            break;
-        }
+        drop(guard);
     }
 
-    connection.flush()
+    shutdown::report(0);
 }
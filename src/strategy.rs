@@ -0,0 +1,210 @@
+// Ways of handling connections, to pick from in code. Each one is a version of the server from
+// the post, answering with whatever handler it's given and tuned by the same `Config`.
+//
+// futures and final aren't here: there's only one of their scheduler per process.
+use crate::config::Config;
+use crate::handler::SharedHandler;
+use crate::server::Server;
+use crate::{busted_polling, mio, multithread, nonblocking, nonblocking_spin, simple, threadpool};
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+pub trait ServerStrategy: Send + Sync + 'static {
+    // Answer connections on `listener` with `handler` until shutdown is requested.
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler);
+
+    // Listen on `config.addr` and serve on a thread of its own, returning once it's ready. The
+    // thread shares the strategy, along with any settings it carries.
+    fn start(self: Arc<Self>, config: Config, handler: SharedHandler) -> io::Result<Server> {
+        Server::start(
+            move |listener, config| self.serve(listener, config, handler),
+            config,
+        )
+    }
+}
+
+// One connection at a time, with blocking I/O.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blocking;
+
+// A thread per connection, up to `max_connections`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadPerConnection;
+
+// A fixed pool of threads fed by a bounded queue, sized by `config.pool`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadPool;
+
+// Non-blocking I/O in a thread per connection, spinning while it waits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spin;
+
+// Non-blocking I/O with every connection on one thread, each tracked by a state machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StateMachine;
+
+// A single-threaded epoll event loop. The work holds up every other connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Epoll;
+
+// Futures on our own runtime, built on mio, where waiting doesn't hold anything up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CustomAsync;
+
+impl ServerStrategy for Blocking {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        simple::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for ThreadPerConnection {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        multithread::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for ThreadPool {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        threadpool::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for Spin {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        nonblocking_spin::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for StateMachine {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        nonblocking::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for Epoll {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        busted_polling::serve(listener, config, handler)
+    }
+}
+
+impl ServerStrategy for CustomAsync {
+    fn serve(&self, listener: TcpListener, config: Config, handler: SharedHandler) {
+        mio::serve(listener, config, handler)
+    }
+}
+
+// Makes a strategy with its default settings.
+pub type MakeStrategy = fn() -> Arc<dyn ServerStrategy>;
+
+// Every strategy, by name.
+pub const STRATEGIES: &[(&str, MakeStrategy)] = &[
+    ("blocking", || Arc::new(Blocking)),
+    ("thread-per-connection", || Arc::new(ThreadPerConnection)),
+    ("thread-pool", || Arc::new(ThreadPool)),
+    ("spin", || Arc::new(Spin)),
+    ("state-machine", || Arc::new(StateMachine)),
+    ("epoll", || Arc::new(Epoll)),
+    ("custom-async", || Arc::new(CustomAsync)),
+];
+
+pub fn by_name(name: &str) -> Option<Arc<dyn ServerStrategy>> {
+    STRATEGIES
+        .iter()
+        .find(|(strategy, _)| *strategy == name)
+        .map(|&(_, make)| make())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn every_strategy_runs_the_handler() {
+        // answers with the request line, so we can tell it saw the request
+        let echo: SharedHandler = Arc::new(|request: &[u8]| {
+            let line = request.split(|&b| b == b'\r').next().unwrap();
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                line.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(line);
+            response
+        });
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+
+        let servers: Vec<_> = STRATEGIES
+            .iter()
+            .map(|&(name, make)| (name, make().start(config, echo.clone()).unwrap()))
+            .collect();
+        for (name, server) in &servers {
+            let mut stream = TcpStream::connect(server.addr()).unwrap();
+            stream
+                .write_all(format!("GET /{name} HTTP/1.1\r\n\r\n").as_bytes())
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(
+                response.ends_with(&format!("\r\n\r\nGET /{name} HTTP/1.1")),
+                "{name}: {response:?}"
+            );
+        }
+
+        // and the same again with one picked by its type
+        let server = Arc::new(Epoll).start(config, echo).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"GET /again HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("GET /again HTTP/1.1"), "{response:?}");
+    }
+
+    #[test]
+    fn finds_strategies_by_name() {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+        let server = by_name("epoll")
+            .unwrap()
+            .start(config, testing::hello_world())
+            .unwrap();
+        let response = testing::simultaneous_requests(server.addr(), 1).remove(0);
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+
+        assert!(by_name("busted_polling").is_none());
+    }
+
+    // A strategy with settings of its own: it answers with the handler it was made with, in
+    // place of the one it's started with.
+    struct Answering(SharedHandler);
+
+    impl ServerStrategy for Answering {
+        fn serve(&self, listener: TcpListener, config: Config, _: SharedHandler) {
+            multithread::serve(listener, config, self.0.clone())
+        }
+    }
+
+    #[test]
+    fn strategies_carry_their_own_settings() {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            ..Config::default()
+        };
+        let answer: SharedHandler = Arc::new(|_: &[u8]| {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nhi\n".to_vec()
+        });
+        let server = Arc::new(Answering(answer))
+            .start(config, testing::hello_world())
+            .unwrap();
+        let response = testing::simultaneous_requests(server.addr(), 1).remove(0);
+        assert!(response.ends_with("\r\n\r\nhi\n"), "{response:?}");
+    }
+}
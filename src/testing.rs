@@ -0,0 +1,500 @@
+// Helpers shared by the servers' tests.
+use crate::config::Config;
+use crate::handler::{HelloWorld, SharedHandler};
+use crate::procfs;
+use crate::server::Server;
+use crate::timeout::Timeouts;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+// A version's `serve`, for the checks every version goes through. Each check takes the name of
+// the test running it, for those that re-run the test in a child process, and starts the server
+// with whatever settings it needs.
+pub type Serve = fn(TcpListener, Config, SharedHandler);
+
+// What the test harness calls the test `$name` in the module this is used in: its path, without
+// the crate's name. Checks that re-run their test in a child process need it.
+macro_rules! test_name {
+    ($name:ident) => {
+        concat!(module_path!(), "::", stringify!($name))
+            .split_once("::")
+            .unwrap()
+            .1
+    };
+}
+pub(crate) use test_name;
+
+// What the servers answer with in their tests.
+pub fn hello_world() -> SharedHandler {
+    Arc::new(HelloWorld)
+}
+
+// Run a server on an ephemeral port in the background, returning the address it listens on.
+//
+// The server thread is never stopped, it goes away with the test process.
+pub fn spawn_server(serve: impl FnOnce(TcpListener) + Send + 'static) -> SocketAddr {
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        ..Config::default()
+    };
+    Server::start(|listener, _| serve(listener), config)
+        .unwrap()
+        .addr()
+}
+
+// Write `contents` to a fresh file in the temp directory, returning its path.
+//
+// Like the servers, the files are left for the OS to clean up.
+pub fn temp_file(contents: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "learning-async-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+// Connect `clients` clients before any of them sends a request, so that the connections
+// all queue up in the listener's backlog at once, then return each client's response.
+pub fn simultaneous_requests(addr: SocketAddr, clients: usize) -> Vec<String> {
+    send_requests(connect_clients(addr, clients))
+}
+
+pub fn connect_clients(addr: SocketAddr, clients: usize) -> Vec<TcpStream> {
+    (0..clients)
+        .map(|_| {
+            let connection = TcpStream::connect(addr).unwrap();
+            // fail rather than hang if the server never gets to us
+            connection
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            connection
+        })
+        .collect()
+}
+
+// Send a request on each connection, then return each response.
+pub fn send_requests(mut connections: Vec<TcpStream>) -> Vec<String> {
+    for connection in &mut connections {
+        connection.write_all(REQUEST).unwrap();
+    }
+
+    connections
+        .into_iter()
+        .map(|mut connection| {
+            let mut response = String::new();
+            connection.read_to_string(&mut response).unwrap();
+            response
+        })
+        .collect()
+}
+
+// Check that a server answers a crowd of clients that all arrive at once.
+pub fn check_simultaneous_clients(_test: &str, serve: Serve) {
+    let server = start_server(serve, Config::default());
+    for response in simultaneous_requests(server.addr(), 64) {
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+    }
+}
+
+// Check that a server limited to a couple of connections leaves the next client waiting until
+// one of those finishes.
+pub fn check_connection_limit(_test: &str, serve: Serve) {
+    let max = 2;
+    let config = Config {
+        max_connections: max,
+        ..Config::default()
+    };
+    let addr = spawn_server(move |listener| serve(listener, config, hello_world()));
+
+    // take up every slot with clients that haven't finished their requests
+    let mut slow = connect_clients(addr, max);
+    for connection in &mut slow {
+        connection.write_all(&REQUEST[..10]).unwrap();
+    }
+    sleep(Duration::from_millis(100));
+
+    // the next client connects (the kernel does that for us) but isn't served
+    let mut waiting = connect_clients(addr, 1).remove(0);
+    waiting.write_all(REQUEST).unwrap();
+    waiting
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut byte = [0];
+    let e = waiting.read(&mut byte).unwrap_err();
+    assert!(
+        matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        "{e}"
+    );
+
+    // until one of the others is done
+    let mut first = slow.remove(0);
+    first.write_all(&REQUEST[10..]).unwrap();
+    let mut response = String::new();
+    first.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+
+    waiting
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut response = String::new();
+    waiting.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+}
+
+// Timeouts short enough to test with.
+fn short_timeouts() -> Timeouts {
+    Timeouts {
+        idle: Duration::from_millis(200),
+        header: Duration::from_millis(200),
+        ..Timeouts::default()
+    }
+}
+
+// Check that a server with `short_timeouts` gives up on clients that are too slow to send their
+// request, and keeps serving everyone else.
+pub fn check_timeouts(_test: &str, serve: Serve) {
+    let config = Config {
+        timeouts: short_timeouts(),
+        ..Config::default()
+    };
+    let addr = spawn_server(move |listener| serve(listener, config, hello_world()));
+
+    // a client that never sends anything is hung up on
+    let mut idle = connect_clients(addr, 1).remove(0);
+    let mut response = String::new();
+    idle.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "");
+
+    // a client trickling in its request gets a 408, even though it never goes quiet for long
+    let mut slow = connect_clients(addr, 1).remove(0);
+    for byte in REQUEST.chunks(1) {
+        if slow.write_all(byte).is_err() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    let mut response = String::new();
+    slow.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"), "{response:?}");
+
+    let response = simultaneous_requests(addr, 1).remove(0);
+    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+}
+
+// Set when a test re-runs itself in a child process, see `ChildServer`.
+const CHILD_ENV: &str = "TEST_SERVER_CHILD";
+
+// How many file descriptors the child's server gets beyond those it starts with.
+const SPARE_FDS: u64 = 8;
+
+// A server running in a child process, for tests that need a process of their own.
+//
+// The calling test re-runs itself with CHILD_ENV set, and in there `setup` runs before the
+// server starts on an ephemeral port.
+pub struct ChildServer {
+    child: Child,
+    pub addr: SocketAddr,
+    output: Option<JoinHandle<Vec<String>>>,
+}
+
+impl ChildServer {
+    // `test` is the full name of the calling test. Returns `None` in the child, once the server
+    // has returned.
+    pub fn start(
+        test: &str,
+        setup: impl FnOnce(),
+        serve: impl FnOnce(TcpListener),
+    ) -> Option<Self> {
+        if std::env::var(CHILD_ENV).as_deref() == Ok(test) {
+            setup();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            println!("listening on {}", listener.local_addr().unwrap());
+            serve(listener);
+            return None;
+        }
+
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--nocapture", "--test-threads=1"])
+            .env(CHILD_ENV, test)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let addr = loop {
+            let Some(Ok(line)) = lines.next() else {
+                let _ = child.kill();
+                panic!("child exited before listening");
+            };
+            // the test harness doesn't end its own line before running the test
+            if let Some((_, addr)) = line.split_once("listening on ") {
+                break addr.parse().unwrap();
+            }
+        };
+
+        // keep draining the child's output so it never blocks on a full pipe
+        let output = spawn(move || lines.map_while(Result::ok).collect());
+
+        Some(ChildServer {
+            child,
+            addr,
+            output: Some(output),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        self.child.try_wait().unwrap().is_none()
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        procfs::cpu_time(&self.child.id().to_string()).unwrap()
+    }
+
+    pub fn signal(&self, signal: libc::c_int) {
+        assert_eq!(
+            unsafe { libc::kill(self.child.id() as libc::pid_t, signal) },
+            0
+        );
+    }
+
+    // Wait for the child to exit, killing it if it takes longer than `timeout`.
+    // Returns whether it exited successfully, and what it printed.
+    pub fn wait(mut self, timeout: Duration) -> (bool, Vec<String>) {
+        let deadline = Instant::now() + timeout;
+        let success = loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                break status.success();
+            }
+            if Instant::now() >= deadline {
+                break false;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let output = self.output.take().unwrap().join().unwrap();
+        (success, output)
+    }
+}
+
+impl Drop for ChildServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Check that a server keeps going when it runs out of file descriptors.
+//
+// The server runs in a child process with RLIMIT_NOFILE barely above what's already open,
+// while this process connects more clients than the child can hold at once.
+pub fn check_fd_exhaustion(test: &str, serve: Serve) {
+    let serve = |listener| serve(listener, Config::default(), hello_world());
+    let Some(mut server) = ChildServer::start(test, lower_fd_limit, serve) else {
+        return;
+    };
+
+    // give the server time to accept as many connections as it can before any finish
+    let connections = connect_clients(server.addr, 32);
+    sleep(Duration::from_millis(200));
+
+    for response in send_requests(connections) {
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+    }
+    assert!(server.is_running(), "server exited");
+
+    let (_, output) = server.wait(Duration::ZERO);
+    assert!(
+        output.iter().any(|line| line.contains("pausing")),
+        "server never ran out of file descriptors"
+    );
+}
+
+// Check that a server with no clients leaves the CPU alone, and say how much it used.
+pub fn check_idle_cpu(test: &str, serve: Serve) {
+    let serve = |listener| serve(listener, Config::default(), hello_world());
+    let Some(server) = ChildServer::start(test, || {}, serve) else {
+        return;
+    };
+
+    // let it settle after starting up
+    sleep(Duration::from_millis(100));
+    let before = server.cpu_time();
+    let window = Duration::from_millis(500);
+    sleep(window);
+    let used = server.cpu_time() - before;
+
+    println!("used {used:?} of CPU in {window:?} with nothing to do");
+    assert!(
+        used < window / 10,
+        "used {used:?} of CPU in {window:?} while idle"
+    );
+}
+
+// Start `serve` on an ephemeral port with `config`'s other settings.
+pub fn start_server(serve: Serve, config: Config) -> Server {
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        ..config
+    };
+    Server::start(
+        move |listener, config| serve(listener, config, hello_world()),
+        config,
+    )
+    .unwrap()
+}
+
+// Wait for `server` to stop, failing if it takes longer than `timeout`.
+pub fn join_within(server: Server, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while !server.is_finished() {
+        assert!(Instant::now() < deadline, "server didn't stop");
+        sleep(Duration::from_millis(10));
+    }
+    server.join().unwrap();
+}
+
+// Check that once asked to shut down a server stops accepting, finishes the request it's in the
+// middle of, and returns.
+pub fn check_graceful_shutdown(_test: &str, serve: Serve) {
+    let server = start_server(serve, Config::default());
+
+    // start a request, but don't finish it yet
+    let mut in_flight = connect_clients(server.addr(), 1).remove(0);
+    in_flight.write_all(&REQUEST[..10]).unwrap();
+    sleep(Duration::from_millis(100));
+
+    server.shutdown();
+    sleep(Duration::from_millis(100));
+
+    // a connection after that is either refused outright or never answered
+    let late = TcpStream::connect(server.addr()).ok().map(|mut late| {
+        late.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let _ = late.write_all(REQUEST);
+        late
+    });
+
+    // the request we started still gets its response
+    in_flight.write_all(&REQUEST[10..]).unwrap();
+    let mut response = String::new();
+    in_flight.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+
+    join_within(server, Duration::from_secs(5));
+
+    if let Some(mut late) = late {
+        let mut response = String::new();
+        let _ = late.read_to_string(&mut response);
+        assert_eq!(response, "");
+    }
+}
+
+// Check that a server given new settings uses them for new connections, leaving the one it's in
+// the middle of alone, and that it keeps its address.
+pub fn check_reload(_test: &str, serve: Serve) {
+    let server = start_server(serve, Config::default());
+
+    // start a request under the default timeouts
+    let mut in_flight = connect_clients(server.addr(), 1).remove(0);
+    in_flight.write_all(&REQUEST[..10]).unwrap();
+    sleep(Duration::from_millis(100));
+
+    // somewhere else to listen, which needs a restart
+    let config = Config {
+        addr: "127.0.0.1:1".parse().unwrap(),
+        timeouts: short_timeouts(),
+        ..Config::default()
+    };
+    server.reload(config);
+    sleep(Duration::from_millis(400));
+
+    // it's taken longer than the new timeouts allow, but it started under the old ones
+    in_flight.write_all(&REQUEST[10..]).unwrap();
+    let mut response = String::new();
+    in_flight.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("Hello world!\n"), "{response:?}");
+
+    // a new connection, on the same address, that's as slow gets timed out
+    let mut slow = connect_clients(server.addr(), 1).remove(0);
+    slow.write_all(&REQUEST[..10]).unwrap();
+    let start = Instant::now();
+    let mut response = String::new();
+    slow.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"), "{response:?}");
+    assert!(
+        start.elapsed() < Duration::from_secs(5),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+fn lower_fd_limit() {
+    let open = std::fs::read_dir("/proc/self/fd").unwrap().count() as u64;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+        limit.rlim_cur = open + SPARE_FDS;
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+    }
+}
+
+// Abort a connection with a TCP reset instead of closing it gracefully.
+pub fn reset(connection: TcpStream) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            connection.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(result, 0);
+    drop(connection);
+}
+
+// Reset connections at awkward moments, then check the server still answers.
+pub fn check_survives_resets(_test: &str, serve: Serve) {
+    let addr = start_server(serve, Config::default()).addr();
+
+    // halfway through the request, so reading it fails
+    let mut connection = TcpStream::connect(addr).unwrap();
+    connection.write_all(&REQUEST[..10]).unwrap();
+    sleep(Duration::from_millis(50));
+    reset(connection);
+
+    // right after the request, so writing the response fails
+    let mut connection = TcpStream::connect(addr).unwrap();
+    connection.write_all(REQUEST).unwrap();
+    reset(connection);
+    sleep(Duration::from_millis(50));
+
+    for response in simultaneous_requests(addr, 4) {
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+    }
+}
@@ -0,0 +1,375 @@
+// Uses blocking I/O on a fixed pool of worker threads, fed through a bounded queue
+// Like multithread, but the number of threads doesn't grow with the number of clients
+use crate::accept::{self, Recovery};
+use crate::config::Config;
+use crate::error::ConnectionError;
+use crate::handler::{handle_blocking, SharedHandler};
+use crate::limit::{self, InFlight, InFlightGuard, Limit};
+use crate::server;
+use crate::shutdown::{self, Signals};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::Instant;
+
+// What to do with a new connection when every worker is busy and the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // stop accepting until there's room, leaving clients in the kernel backlog
+    Block,
+    // turn the new connection away with a 503
+    Reject,
+    // turn away the connection that's been waiting longest with a 503, and queue the new one
+    DropOldest,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            _ => Err(format!(
+                "unknown queue policy {s:?}, expected block, reject or drop-oldest"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub workers: usize,
+    // connections waiting for a worker
+    pub queue_size: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            workers: 16,
+            queue_size: 64,
+            policy: QueuePolicy::Block,
+        }
+    }
+}
+
+// Sent to clients we don't have room for.
+const BUSY: &[u8] = concat!(
+    "HTTP/1.1 503 Service Unavailable\r\n",
+    "Content-Length: 12\r\n",
+    "Connection: close\r\n\r\n",
+    "Server busy\n"
+)
+.as_bytes();
+
+// a connection waiting for a worker, with the settings it was accepted under, counted as in
+// flight from the moment it's accepted
+type Job = (TcpStream, Config, InFlightGuard);
+
+struct Queue {
+    jobs: Mutex<Jobs>,
+    // signalled when a job is pushed or the queue is closed
+    ready: Condvar,
+    // signalled when a worker takes a job
+    space: Condvar,
+    capacity: usize,
+}
+
+struct Jobs {
+    waiting: VecDeque<Job>,
+    // no more jobs are coming, workers exit once the queue is empty
+    closed: bool,
+}
+
+impl Queue {
+    // Queue a job, applying `policy` if the queue is full.
+    fn push(&self, job: Job, policy: QueuePolicy, signals: &Signals) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.waiting.len() >= self.capacity {
+            match policy {
+                QueuePolicy::Block => {
+                    // checking back in case we're asked to shut down meanwhile, in which case
+                    // there won't be room for this one
+                    while jobs.waiting.len() >= self.capacity {
+                        if signals.requested() {
+                            drop(jobs);
+                            reject(job);
+                            return;
+                        }
+                        jobs = self
+                            .space
+                            .wait_timeout(jobs, limit::CHECK_INTERVAL)
+                            .unwrap()
+                            .0;
+                    }
+                }
+                QueuePolicy::Reject => {
+                    drop(jobs);
+                    reject(job);
+                    return;
+                }
+                QueuePolicy::DropOldest => {
+                    let oldest = jobs.waiting.pop_front().unwrap();
+                    jobs.waiting.push_back(job);
+                    drop(jobs);
+                    self.ready.notify_one();
+                    reject(oldest);
+                    return;
+                }
+            }
+        }
+        jobs.waiting.push_back(job);
+        drop(jobs);
+        self.ready.notify_one();
+    }
+
+    // Wait for a job, or `None` once the queue is closed and empty.
+    fn pop(&self) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.waiting.pop_front() {
+                drop(jobs);
+                self.space.notify_one();
+                return Some(job);
+            }
+            if jobs.closed {
+                return None;
+            }
+            jobs = self.ready.wait(jobs).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.jobs.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+// Tell a client we're too busy for it, without waiting on it.
+fn reject((mut connection, _, _in_flight): Job) {
+    println!("too busy, turning a connection away");
+    let sent = connection
+        .write_all(BUSY)
+        .and_then(|()| connection.shutdown(Shutdown::Write));
+    if let Err(e) = sent {
+        println!("failed to handle connection: {}", ConnectionError::from(e));
+    }
+
+    // closing with some of the request unread resets the connection, which can lose the
+    // response on its way to the client, so throw away whatever has arrived
+    if connection.set_nonblocking(true).is_ok() {
+        let _ = connection.read(&mut [0; 1024]);
+    }
+}
+
+pub fn serve(listener: TcpListener, mut config: Config, handler: SharedHandler) {
+    assert!(
+        config.pool.workers > 0,
+        "the pool needs at least one worker"
+    );
+    assert!(
+        config.pool.queue_size > 0,
+        "the queue needs room for at least one connection"
+    );
+    let signals = shutdown::current().unwrap();
+    let in_flight = InFlight::default();
+    let mut limit = Limit::new(config.max_connections);
+    let queue = Arc::new(Queue {
+        jobs: Mutex::new(Jobs {
+            waiting: VecDeque::new(),
+            closed: false,
+        }),
+        ready: Condvar::new(),
+        space: Condvar::new(),
+        capacity: config.pool.queue_size,
+    });
+
+    for _ in 0..config.pool.workers {
+        let queue = queue.clone();
+        let handler = handler.clone();
+        spawn(move || {
+            while let Some((connection, config, _in_flight)) = queue.pop() {
+                if let Err(e) =
+                    handle_blocking(connection, &config.timeouts, config.work, &*handler)
+                {
+                    println!("failed to handle connection: {e}")
+                }
+            }
+        });
+    }
+    server::ready(&in_flight);
+
+    while signals.wait_for_connection(&listener).unwrap() {
+        if config.reload(&signals) {
+            limit.set_max(config.max_connections);
+        }
+        // at the limit, counting the connections in the queue: no more until one finishes,
+        // checking back in case we're asked to shut down meanwhile
+        if !limit.has_room(in_flight.count()) {
+            in_flight.wait_below(limit.max(), Instant::now() + limit::CHECK_INTERVAL);
+            continue;
+        }
+
+        let connection = match listener.accept() {
+            Ok((connection, _)) => connection,
+            Err(e) => {
+                if accept::recover(e) == Recovery::Backoff {
+                    sleep(accept::BACKOFF);
+                }
+                continue;
+            }
+        };
+
+        queue.push(
+            (connection, config, in_flight.start()),
+            config.pool.policy,
+            &signals,
+        );
+    }
+
+    // stop accepting new connections, and give the ones we have time to finish
+    // (the workers exit once the queue is empty)
+    drop(listener);
+    queue.close();
+    shutdown::report(in_flight.wait(Instant::now() + shutdown::DRAIN_TIMEOUT));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::time::Duration;
+
+    fn pool(policy: QueuePolicy) -> Config {
+        Config {
+            pool: PoolConfig {
+                workers: 1,
+                queue_size: 1,
+                policy,
+            },
+            ..Config::default()
+        }
+    }
+
+    // Occupy the only worker and the only queue slot with clients that haven't finished their
+    // requests.
+    fn fill_pool(addr: std::net::SocketAddr) -> Vec<TcpStream> {
+        let mut slow = Vec::new();
+        for _ in 0..2 {
+            let mut connection = testing::connect_clients(addr, 1).remove(0);
+            connection.write_all(&testing::REQUEST[..10]).unwrap();
+            slow.push(connection);
+            sleep(Duration::from_millis(100));
+        }
+        slow
+    }
+
+    fn finish(mut connection: TcpStream) -> String {
+        connection.write_all(&testing::REQUEST[10..]).unwrap();
+        let mut response = String::new();
+        connection.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn blocks_when_the_queue_is_full() {
+        let addr = testing::spawn_server(|listener| {
+            serve(listener, pool(QueuePolicy::Block), testing::hello_world())
+        });
+        let mut slow = fill_pool(addr);
+
+        // the next client is left waiting
+        let mut waiting = testing::connect_clients(addr, 1).remove(0);
+        waiting.write_all(testing::REQUEST).unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(waiting.read(&mut [0]).is_err());
+
+        // until there's room for it
+        for connection in slow.drain(..) {
+            let response = finish(connection);
+            assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        }
+        waiting
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut response = String::new();
+        waiting.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+    }
+
+    #[test]
+    fn rejects_when_the_queue_is_full() {
+        let addr = testing::spawn_server(|listener| {
+            serve(listener, pool(QueuePolicy::Reject), testing::hello_world())
+        });
+        let mut slow = fill_pool(addr);
+
+        let rejected = testing::simultaneous_requests(addr, 1).remove(0);
+        assert!(rejected.starts_with("HTTP/1.1 503"), "{rejected:?}");
+
+        for connection in slow.drain(..) {
+            let response = finish(connection);
+            assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        }
+    }
+
+    #[test]
+    fn drops_the_oldest_when_the_queue_is_full() {
+        let addr = testing::spawn_server(|listener| {
+            serve(
+                listener,
+                pool(QueuePolicy::DropOldest),
+                testing::hello_world(),
+            )
+        });
+        let mut slow = fill_pool(addr);
+        let mut newest = testing::connect_clients(addr, 1).remove(0);
+        newest.write_all(testing::REQUEST).unwrap();
+
+        // the queued client is turned away to make room
+        let mut oldest = slow.pop().unwrap();
+        let mut response = String::new();
+        oldest.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response:?}");
+
+        let response = finish(slow.pop().unwrap());
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        let mut response = String::new();
+        newest.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("Hello world!\n"), "{response:?}");
+    }
+
+    #[test]
+    fn stops_waiting_for_room_on_shutdown() {
+        let server = testing::start_server(serve, pool(QueuePolicy::Block));
+        let slow = fill_pool(server.addr());
+        let mut waiting = testing::connect_clients(server.addr(), 1).remove(0);
+        waiting.write_all(testing::REQUEST).unwrap();
+        sleep(Duration::from_millis(100));
+
+        // the client we were waiting to find room for is turned away rather than holding up
+        // the shutdown
+        server.shutdown();
+        waiting
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = String::new();
+        waiting.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{response:?}");
+
+        // while the ones already in the pool are still answered
+        for connection in slow {
+            let response = finish(connection);
+            assert!(response.ends_with("Hello world!\n"), "{response:?}");
+        }
+        testing::join_within(server, Duration::from_secs(5));
+    }
+}
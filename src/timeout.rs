@@ -0,0 +1,85 @@
+// How long clients get for each part of a connection, so one that trickles its request in a
+// byte at a time, or never sends anything, can't hold on to a thread or a connection slot forever.
+// We don't do keep-alive or read request bodies, so there's no body or between-requests timeout:
+// the idle timeout covers waiting for the request to start.
+use crate::error::ConnectionError;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    // from accepting the connection to the first byte of the request
+    pub idle: Duration,
+    // from the first byte of the request to the end of its headers
+    pub header: Duration,
+    // for writing the whole response
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: Duration::from_secs(10),
+            header: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+        }
+    }
+}
+
+// Sent to clients that started a request but didn't finish it in time.
+pub const REQUEST_TIMEOUT: &[u8] = concat!(
+    "HTTP/1.1 408 Request Timeout\r\n",
+    "Content-Length: 0\r\n",
+    "Connection: close\r\n\r\n"
+)
+.as_bytes();
+
+// Give up on a client that's taken too long with its request, having `read` bytes of it.
+// Clients that never started don't get a response, ones part way through get a 408.
+pub fn request_timed_out(connection: &mut impl Write, read: usize) -> ConnectionError {
+    let what = if read == 0 {
+        "timed out waiting for a request"
+    } else {
+        // best effort, we're not going to wait around for a client this slow
+        let _ = connection.write(REQUEST_TIMEOUT);
+        "timed out reading the request"
+    };
+    ConnectionError::from(io::Error::new(io::ErrorKind::TimedOut, what))
+}
+
+// Read from a blocking socket, giving up with `TimedOut` at `deadline`.
+pub fn read_before(
+    connection: &mut TcpStream,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> io::Result<usize> {
+    connection.set_read_timeout(Some(remaining(deadline)?))?;
+    match connection.read(buf) {
+        // that's how an expired socket timeout shows up
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::ErrorKind::TimedOut.into()),
+        result => result,
+    }
+}
+
+// Write to a blocking socket, giving up with `TimedOut` at `deadline`.
+pub fn write_before(
+    connection: &mut TcpStream,
+    buf: &[u8],
+    deadline: Instant,
+) -> io::Result<usize> {
+    connection.set_write_timeout(Some(remaining(deadline)?))?;
+    match connection.write(buf) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::ErrorKind::TimedOut.into()),
+        result => result,
+    }
+}
+
+// socket timeouts can't be zero, that means no timeout at all
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    Ok(remaining)
+}
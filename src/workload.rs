@@ -0,0 +1,199 @@
+// What a request does between arriving and getting its response, standing in for real work.
+// Waiting is cheap for every server, but the other profiles tie up whichever thread runs them,
+// which is what shows where each way of handling connections comes out ahead.
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::fs::File;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::hint::black_box;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    // wait a fixed time, like a call to a backend
+    Sleep(Duration),
+    // wait a time picked evenly from a range
+    RandomSleep { min: Duration, max: Duration },
+    // spin the CPU hashing this many times
+    Cpu { iterations: u64 },
+    // allocate and touch this many bytes
+    Alloc { bytes: usize },
+    // read this many bytes from a file, with blocking reads
+    Read { bytes: usize },
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload::Sleep(Duration::from_millis(10))
+    }
+}
+
+pub const PROFILES: &str =
+    "sleep:MS, random:MIN-MAX (in ms), cpu:ITERATIONS, alloc:BYTES or read:BYTES";
+
+impl FromStr for Workload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("unknown workload {s:?}, expected {PROFILES}");
+        let (profile, amount) = s.split_once(':').ok_or_else(invalid)?;
+        let number = |n: &str| n.parse::<u64>().map_err(|_| invalid());
+        match profile {
+            "sleep" => Ok(Workload::Sleep(Duration::from_millis(number(amount)?))),
+            "random" => {
+                let (min, max) = amount.split_once('-').ok_or_else(invalid)?;
+                let (min, max) = (number(min)?, number(max)?);
+                if min > max {
+                    return Err(format!("workload {s:?} has its range the wrong way round"));
+                }
+                Ok(Workload::RandomSleep {
+                    min: Duration::from_millis(min),
+                    max: Duration::from_millis(max),
+                })
+            }
+            "cpu" => Ok(Workload::Cpu {
+                iterations: number(amount)?,
+            }),
+            "alloc" => Ok(Workload::Alloc {
+                bytes: number(amount)? as usize,
+            }),
+            "read" => Ok(Workload::Read {
+                bytes: number(amount)? as usize,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Workload {
+    // How long to wait, for the profiles that only wait. Servers with timers can use one instead
+    // of calling `run`.
+    pub fn delay(&self) -> Option<Duration> {
+        match *self {
+            Workload::Sleep(delay) => Some(delay),
+            Workload::RandomSleep { min, max } => {
+                let spread = (max - min).as_micros() as u64;
+                Some(min + Duration::from_micros(random() % (spread + 1)))
+            }
+            _ => None,
+        }
+    }
+
+    // Do the work, blocking the calling thread until it's done.
+    pub fn run(&self) {
+        if let Some(delay) = self.delay() {
+            sleep(delay);
+            return;
+        }
+        match *self {
+            Workload::Cpu { iterations } => {
+                let mut hash = 0;
+                for i in 0..iterations {
+                    let mut hasher = DefaultHasher::new();
+                    (hash ^ i).hash(&mut hasher);
+                    hash = hasher.finish();
+                }
+                black_box(hash);
+            }
+            Workload::Alloc { bytes } => {
+                let mut memory = vec![0u8; bytes];
+                // zeroed memory from the OS isn't really there until it's written to
+                for page in memory.iter_mut().step_by(4096) {
+                    *page = 1;
+                }
+                black_box(memory);
+            }
+            Workload::Read { bytes } => {
+                if let Err(e) = read(bytes) {
+                    println!("simulated read failed: {e}");
+                }
+            }
+            Workload::Sleep(_) | Workload::RandomSleep { .. } => unreachable!(),
+        }
+    }
+}
+
+// Read `bytes` from our own executable, which is always there and a few megabytes long,
+// starting over from the beginning if it isn't long enough. After the first time it's mostly
+// coming from the page cache, but each read is still a blocking system call.
+fn read(bytes: usize) -> io::Result<()> {
+    let mut buf = [0u8; 64 * 1024];
+    let mut file = File::open("/proc/self/exe")?;
+    let mut left = bytes;
+    while left > 0 {
+        let want = left.min(buf.len());
+        let n = match file.read(&mut buf[..want])? {
+            0 => {
+                file = File::open("/proc/self/exe")?;
+                continue;
+            }
+            n => n,
+        };
+        black_box(&buf[..n]);
+        left -= n;
+    }
+    Ok(())
+}
+
+// Good enough randomness for spreading out sleeps, without a dependency: the standard library
+// seeds each `RandomState` randomly.
+fn random() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles() {
+        for (profile, workload) in [
+            ("sleep:10", Workload::Sleep(Duration::from_millis(10))),
+            (
+                "random:5-50",
+                Workload::RandomSleep {
+                    min: Duration::from_millis(5),
+                    max: Duration::from_millis(50),
+                },
+            ),
+            ("cpu:1000", Workload::Cpu { iterations: 1000 }),
+            ("alloc:4096", Workload::Alloc { bytes: 4096 }),
+            ("read:65536", Workload::Read { bytes: 65536 }),
+        ] {
+            assert_eq!(profile.parse(), Ok(workload));
+        }
+        for bad in ["sleep", "sleep:soon", "random:50-5", "random:5", "gpu:1"] {
+            assert!(bad.parse::<Workload>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn picks_random_delays_in_range() {
+        let (min, max) = (Duration::from_millis(5), Duration::from_millis(7));
+        let workload = Workload::RandomSleep { min, max };
+        let delays: Vec<_> = (0..100).map(|_| workload.delay().unwrap()).collect();
+        assert!(delays.iter().all(|delay| (min..=max).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn does_the_work() {
+        let start = Instant::now();
+        Workload::Sleep(Duration::from_millis(20)).run();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // the rest only have to finish, and not wait around
+        for workload in [
+            Workload::Cpu { iterations: 10_000 },
+            Workload::Alloc { bytes: 1 << 20 },
+            // more than the executable holds, so it goes round again
+            Workload::Read { bytes: 256 << 20 },
+        ] {
+            assert_eq!(workload.delay(), None);
+            workload.run();
+        }
+        assert!(read(1024).is_ok());
+    }
+}
@@ -0,0 +1,359 @@
+// Runs every version of the server as its own process, the way it's run by hand, and checks it
+// copes with clients that don't behave: requests sent a byte at a time, requests too big for its
+// buffer and clients that go away halfway through. Whatever happened to the last client, the
+// next one should still get its response.
+use learning_async_rust_web_servers::VARIANTS;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+const RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nContent-Length: 13\nConnection: close\r\n\r\nHello world!\n";
+
+// Long enough for a server that's stopped answering, without holding up one that hasn't.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+// One of the servers, killed when it goes out of scope.
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Server {
+    fn start(variant: &str) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_learning-async-rust-web-servers"))
+            .args([variant, "--port", "0"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let addr = loop {
+            let line = lines
+                .next()
+                .unwrap_or_else(|| panic!("{variant} exited before it was listening"))
+                .unwrap();
+            if let Some((_, addr)) = line.split_once("listening on ") {
+                break addr.parse().unwrap();
+            }
+        };
+        // some of them print every request, so keep the pipe from filling up
+        spawn(move || lines.for_each(drop));
+        Server { child, addr }
+    }
+
+    fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(PATIENCE)).unwrap();
+        stream
+    }
+
+    // Send a request the ordinary way and check the answer.
+    fn check_still_serving(&mut self, after: &str) {
+        let mut stream = self.connect();
+        stream.write_all(REQUEST).unwrap();
+        let response =
+            read_response(&mut stream).unwrap_or_else(|e| panic!("no response after {after}: {e}"));
+        assert_eq!(response, RESPONSE, "after {after}");
+        assert!(
+            self.child.try_wait().unwrap().is_none(),
+            "the server exited after {after}"
+        );
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Everything the server sends before it closes the connection.
+fn read_response(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+// Close `stream` with a reset instead of the usual goodbye, like a client that crashed.
+fn reset(stream: TcpStream) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+}
+
+fn well_formed(server: &mut Server) {
+    let mut stream = server.connect();
+    stream.write_all(REQUEST).unwrap();
+    assert_eq!(read_response(&mut stream).unwrap(), RESPONSE);
+}
+
+fn byte_at_a_time(server: &mut Server) {
+    let mut stream = server.connect();
+    stream.set_nodelay(true).unwrap();
+    for byte in REQUEST {
+        stream.write_all(&[*byte]).unwrap();
+        sleep(Duration::from_millis(2));
+    }
+    assert_eq!(read_response(&mut stream).unwrap(), RESPONSE);
+    server.check_still_serving("a request sent a byte at a time");
+}
+
+// The servers only read the first kilobyte of a request. Past that they turn the client away
+// with a 431, rather than waiting forever for the end of the headers. futures and final are the
+// post's code as written, which gives up on the client without a word.
+fn oversized(server: &mut Server, tunable: bool) {
+    if tunable {
+        // a kilobyte exactly, so the server reads all of it and nothing's left to reset the
+        // connection before the client sees the response
+        let mut stream = server.connect();
+        let mut request = "GET / HTTP/1.1\r\nX-Padding: ".to_string();
+        request += &"a".repeat(1024 - request.len());
+        stream.write_all(request.as_bytes()).unwrap();
+        let response = read_response(&mut stream).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 431 "), "{response:?}");
+        server.check_still_serving("a request that filled the buffer");
+    }
+
+    // whatever it manages to say to one that keeps going, it carries on
+    let mut stream = server.connect();
+    let padding = "a".repeat(4096);
+    let request = format!("GET / HTTP/1.1\r\nX-Padding: {padding}\r\n\r\n");
+    // the server may have hung up before it all goes
+    let _ = stream.write_all(request.as_bytes());
+    match read_response(&mut stream) {
+        Ok(response) => assert!(
+            response.is_empty() || response.starts_with(b"HTTP/1.1 431 "),
+            "{response:?}"
+        ),
+        // it hung up with some of the request still unread
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+    }
+    server.check_still_serving("an oversized request");
+}
+
+fn abruptly_closed(server: &mut Server) {
+    // connects and leaves without a word
+    drop(server.connect());
+    server.check_still_serving("a client that sent nothing");
+
+    // stops halfway through the request
+    let mut stream = server.connect();
+    stream.write_all(&REQUEST[..10]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_response(&mut stream).unwrap(), b"");
+    server.check_still_serving("a client that stopped halfway through");
+
+    // crashes halfway through the request
+    let mut stream = server.connect();
+    stream.write_all(&REQUEST[..10]).unwrap();
+    reset(stream);
+    server.check_still_serving("a client that reset the connection");
+
+    // sends the request and crashes without reading the response
+    let mut stream = server.connect();
+    stream.write_all(REQUEST).unwrap();
+    reset(stream);
+    server.check_still_serving("a client that didn't wait for the response");
+}
+
+fn check(variant: &str) {
+    let tunable = VARIANTS.iter().any(|v| v.name == variant && v.tunable);
+    let mut server = Server::start(variant);
+    well_formed(&mut server);
+    byte_at_a_time(&mut server);
+    oversized(&mut server, tunable);
+    abruptly_closed(&mut server);
+}
+
+#[test]
+fn simple() {
+    check("simple");
+}
+
+#[test]
+fn multithread() {
+    check("multithread");
+}
+
+#[test]
+fn threadpool() {
+    check("threadpool");
+}
+
+#[test]
+fn nonblocking_spin() {
+    check("nonblocking_spin");
+}
+
+#[test]
+fn nonblocking() {
+    check("nonblocking");
+}
+
+#[test]
+fn busted_polling() {
+    check("busted_polling");
+}
+
+#[test]
+fn mio() {
+    check("mio");
+}
+
+#[test]
+fn futures() {
+    check("futures");
+}
+
+#[test]
+fn r#final() {
+    check("final");
+}
+
+// futures and final are the post's code as written, so rather than quietly ignoring settings
+// they don't have, they refuse them.
+#[test]
+fn untunable_variants_refuse_options() {
+    // even when the setting's no different from the default
+    for (variant, option, value) in [
+        ("futures", "--idle-timeout-ms", "100"),
+        ("final", "--max-connections", "1024"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_learning-async-rust-web-servers"))
+            .args([variant, "--port", "0", option, value])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{variant}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("only takes where to listen"), "{stderr}");
+    }
+}
+
+// The library leaves signals to the command line, which reloads the settings on SIGHUP and shuts
+// the server down on SIGTERM.
+#[test]
+fn reloads_on_sighup_and_stops_on_sigterm() {
+    let path = std::env::temp_dir().join(format!("variants-signals-{}.conf", std::process::id()));
+    std::fs::write(&path, "idle-timeout-ms = 200\n").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_learning-async-rust-web-servers"))
+        .args(["multithread", "--port", "0", "--config"])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (send, lines) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    spawn(move || {
+        stdout
+            .lines()
+            .map_while(Result::ok)
+            .try_for_each(|line| send.send(line))
+    });
+    let signal = |signal| assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, signal) }, 0);
+    let expect = |wanted: &str| loop {
+        let line = lines
+            .recv_timeout(PATIENCE)
+            .unwrap_or_else(|_| panic!("never printed {wanted:?}"));
+        if let Some((_, rest)) = line.split_once(wanted) {
+            break rest.to_string();
+        }
+    };
+    let addr: SocketAddr = expect("listening on ").parse().unwrap();
+
+    std::fs::write(&path, "idle-timeout-ms = 0\n").unwrap();
+    signal(libc::SIGHUP);
+    expect("keeping the current configuration: ");
+    std::fs::write(&path, "idle-timeout-ms = 100\nroute = /health ok\n").unwrap();
+    signal(libc::SIGHUP);
+    // the server takes up new settings when the next client arrives, routes and all
+    sleep(Duration::from_millis(100));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut stream).unwrap();
+    assert!(response.ends_with(b"\r\n\r\nok\n"), "{response:?}");
+    expect("reloaded the configuration");
+    std::fs::write(&path, "listen = 127.0.0.1:1\n").unwrap();
+    signal(libc::SIGHUP);
+    expect("moving needs a restart");
+
+    signal(libc::SIGTERM);
+    expect("all connections finished");
+    let _ = std::fs::remove_file(&path);
+    assert!(child.wait().unwrap().success());
+}
+
+// Given more than one address, the command line starts a server on each, all answering from the
+// same routes.
+#[test]
+fn listens_on_each_address_and_answers_from_its_routes() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_learning-async-rust-web-servers"))
+        .args(["mio", "--listen", "127.0.0.1:0", "--listen", "127.0.0.1:0"])
+        .args(["--route", "/health ok"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let addrs: Vec<SocketAddr> = (0..2)
+        .map(|_| loop {
+            let line = lines
+                .next()
+                .expect("exited before it was listening")
+                .unwrap();
+            if let Some((_, addr)) = line.split_once("listening on ") {
+                break addr.parse().unwrap();
+            }
+        })
+        .collect();
+    assert_ne!(addrs[0], addrs[1]);
+    spawn(move || lines.for_each(drop));
+
+    for addr in addrs {
+        for (path, status) in [("/health", "200 OK"), ("/", "404 Not Found")] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(PATIENCE)).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let response = String::from_utf8(read_response(&mut stream).unwrap()).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}")),
+                "{path}: {response}"
+            );
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// futures and final have no graceful shutdown, so SIGTERM ends them as it would any process.
+#[test]
+fn untunable_variants_stop_on_sigterm() {
+    for variant in ["futures", "final"] {
+        let mut server = Server::start(variant);
+        assert_eq!(
+            unsafe { libc::kill(server.child.id() as libc::pid_t, libc::SIGTERM) },
+            0
+        );
+        let status = server.child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM), "{variant}");
+    }
+}